@@ -62,13 +62,18 @@ pub fn derive_service(input: TokenStream) -> TokenStream {
 				version: env!("CARGO_PKG_VERSION"),
 			};
 
-			#[cfg(debug_assertions)]
 			const FILE_DESCRIPTOR_SET: &'static [u8] = #fd_path;
 
 			type Server = #server<Self>;
 			fn new_server(self) -> Self::Server {
 				Self::Server::new(self)
 			}
+			fn configure_server(
+				server: Self::Server,
+				options: &::runesys::grpc::GrpcOptions,
+			) -> Self::Server {
+				::runesys::configure_server!(server, options)
+			}
 		}
 	};
 
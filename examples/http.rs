@@ -4,7 +4,6 @@ use tonic_health::{
 	pb::{HealthCheckRequest, HealthCheckResponse, health_server::HealthServer},
 	server::WatchStream,
 };
-use tracing::info;
 
 #[derive(Service)]
 #[server(HealthServer)]
@@ -18,7 +17,7 @@ impl NamedService for HelloWorld {
 impl tonic_health::pb::health_server::Health for HelloWorld {
 	async fn check(
 		&self,
-		request: Request<HealthCheckRequest>,
+		_request: Request<HealthCheckRequest>,
 	) -> Result<Response<HealthCheckResponse>, Status> {
 		todo!()
 	}
@@ -27,7 +26,7 @@ impl tonic_health::pb::health_server::Health for HelloWorld {
 
 	async fn watch(
 		&self,
-		request: Request<HealthCheckRequest>,
+		_request: Request<HealthCheckRequest>,
 	) -> Result<Response<Self::WatchStream>, Status> {
 		todo!()
 	}
@@ -37,12 +36,9 @@ impl tonic_health::pb::health_server::Health for HelloWorld {
 async fn main() {
 	HelloWorld {}
 		.builder()
+		.unwrap()
 		// .with_http(axum::Router::new().route("/", axum::routing::get(|| async { "Hello, World!" })))
-		.with_task(async {
-			loop {}
-
-			Ok(())
-		})
+		.with_task(std::future::pending())
 		.run()
 		.await
 		.unwrap();
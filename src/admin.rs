@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use axum::{
+	Json, Router,
+	extract::State,
+	http::StatusCode,
+	response::{IntoResponse, Response},
+	routing::get,
+};
+use serde_json::Value;
+use tonic_health::server::HealthReporter;
+use url::Url;
+
+use crate::{ServiceInfo, health::CheckResults, task::TaskStatuses};
+
+/// Parts of config keys whose values are hidden from `/config`, in tables at any depth.
+const SECRET_KEYS: [&str; 7] = [
+	"key",
+	"password",
+	"passwd",
+	"secret",
+	"token",
+	"dsn",
+	"credential",
+];
+
+#[derive(Clone)]
+struct Admin {
+	info: ServiceInfo,
+	tasks: TaskStatuses,
+	checks: CheckResults,
+}
+
+/// The operational endpoints served on `admin_port`
+///
+/// - `/healthz` and `/readyz`: see [`health::router`](crate::health::router)
+/// - `/version`: the [`ServiceInfo`]
+/// - `/config`: the configuration, with secrets redacted
+/// - `/log-filter`: the log filter currently applied
+/// - `/tasks`: the state of the background tasks
+/// - `/checks`: the latest result of every health check
+pub(crate) fn router(
+	info: ServiceInfo,
+	health: HealthReporter,
+	services: Arc<[&'static str]>,
+	tasks: TaskStatuses,
+	checks: CheckResults,
+) -> Router {
+	Router::new()
+		.route(
+			"/version",
+			get(|State(admin): State<Admin>| async move { Json(admin.info) }),
+		)
+		.route("/config", get(config))
+		.route("/log-filter", get(log_filter))
+		.route(
+			"/tasks",
+			get(|State(admin): State<Admin>| async move { Json(admin.tasks.snapshot()) }),
+		)
+		.route(
+			"/checks",
+			get(|State(admin): State<Admin>| async move { Json(admin.checks.snapshot()) }),
+		)
+		.with_state(Admin {
+			info,
+			tasks,
+			checks,
+		})
+		.merge(crate::health::router(health, services))
+}
+
+async fn config() -> Response {
+	match serde_json::to_value(crate::config::config()) {
+		Ok(mut config) => {
+			redact(&mut config);
+			Json(config).into_response()
+		}
+		Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+	}
+}
+
+async fn log_filter() -> Response {
+	match crate::tracing::log_filter() {
+		Some(filter) => filter.into_response(),
+		None => (StatusCode::NOT_FOUND, "log filter not managed by runesys").into_response(),
+	}
+}
+
+/// Hide values whose key names a secret, and passwords in URLs, at any depth.
+fn redact(value: &mut Value) {
+	match value {
+		Value::Object(map) => {
+			for (key, value) in map {
+				let key = key.to_ascii_lowercase();
+				if !value.is_null() && SECRET_KEYS.iter().any(|secret| key.contains(secret)) {
+					*value = Value::from("[redacted]");
+				} else {
+					redact(value);
+				}
+			}
+		}
+		Value::Array(values) => values.iter_mut().for_each(redact),
+		Value::String(s) => {
+			if let Ok(mut url) = Url::parse(s)
+				&& url.password().is_some()
+			{
+				let _ = url.set_password(Some("redacted"));
+				*s = url.to_string();
+			}
+		}
+		_ => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::*;
+
+	#[test]
+	fn redacts_secrets_at_any_depth() {
+		let mut config = json!({
+			"grpc_port": 50051,
+			"tls_key": null,
+			"postgres_url": "postgres://app:hunter2@db/app",
+			"auth": {
+				"issuer": "https://id.example.com",
+				"client_secret": "s3cr3t",
+				"upstreams": [{ "url": "redis://:pw@cache/" }, { "Sentry_DSN": "https://k@sentry/1" }],
+			},
+			"Credentials": { "user": "app" },
+		});
+		redact(&mut config);
+		assert_eq!(
+			config,
+			json!({
+				"grpc_port": 50051,
+				"tls_key": null,
+				"postgres_url": "postgres://app:redacted@db/app",
+				"auth": {
+					"issuer": "https://id.example.com",
+					"client_secret": "[redacted]",
+					"upstreams": [{ "url": "redis://:redacted@cache/" }, { "Sentry_DSN": "[redacted]" }],
+				},
+				"Credentials": "[redacted]",
+			})
+		);
+	}
+}
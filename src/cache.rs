@@ -4,10 +4,13 @@ use redis::{FromRedisValue, RedisResult, ToRedisArgs, Value};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 fn current_epoch_millis() -> u64 {
-	SystemTime::now()
-		.duration_since(UNIX_EPOCH)
-		.expect("Time went backwards")
-		.as_millis() as u64
+	u64::try_from(
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.expect("Time went backwards")
+			.as_millis(),
+	)
+	.expect("Time went too far forwards")
 }
 
 #[derive(Serialize, Deserialize)]
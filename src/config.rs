@@ -1,26 +1,122 @@
 use std::{
 	net::{IpAddr, Ipv6Addr},
-	sync::{LazyLock, OnceLock},
+	path::{Path, PathBuf},
+	sync::{Arc, LazyLock, OnceLock, RwLock},
 };
 
 use figment::{
 	Figment, Metadata, Profile, Provider,
-	value::{Dict, Map},
+	error::Kind,
+	value::{Dict, Map, Value},
 };
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use url::Url;
 
-use crate::{Service, ServiceInfo};
+use crate::error::{Error, Result};
 
-#[derive(Debug, Deserialize, Serialize)]
+mod reload;
+
+pub(crate) use reload::spawn_watcher;
+pub use reload::{reload, watch};
+
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Config {
 	pub grpc_port: u16,
 
 	#[cfg(feature = "http")]
 	pub http_port: u16,
 
+	/// Serve gRPC and HTTP together on `grpc_port`, telling them apart by `content-type`; the
+	/// gRPC keepalive, concurrency limit and timeout cannot be used with it.
+	#[cfg(feature = "http")]
+	pub single_port: bool,
+
+	/// Largest HTTP request body, in bytes; axum's extractors default to 2 MiB.
+	#[cfg(feature = "http")]
+	pub http_body_limit: Option<usize>,
+	/// How long, in seconds, an HTTP request may take before `408 Request Timeout` is returned.
+	#[cfg(feature = "http")]
+	pub http_timeout: Option<u64>,
+	/// Origins allowed to call the HTTP endpoints from browsers, e.g. `[https://example.com]`, or
+	/// `[*]` for any; CORS is disabled when empty.
+	#[cfg(feature = "http")]
+	pub http_cors_origins: Vec<String>,
+	/// Compress HTTP responses and decompress request bodies with gzip or zstd.
+	#[cfg(all(feature = "http", feature = "compression"))]
+	pub http_compression: bool,
+	/// Record request headers in HTTP traces, except those in `http_sensitive_headers`.
+	#[cfg(feature = "http")]
+	pub http_trace_headers: bool,
+	/// Headers redacted from HTTP traces when `http_trace_headers` is set.
+	#[cfg(feature = "http")]
+	pub http_sensitive_headers: Vec<String>,
+	/// Also serve `/healthz` and `/readyz` on the HTTP router, which must then not route those
+	/// paths itself; `admin_port` always serves them.
+	#[cfg(feature = "http")]
+	pub http_health_probes: bool,
+
 	pub address: IpAddr,
 
+	/// Serve operational endpoints on this port of `address`, away from the public listeners.
+	pub admin_port: Option<u16>,
+
+	/// Serve gRPC on this Unix domain socket instead of `address`:`grpc_port`; TLS is not used
+	/// on Unix sockets.
+	#[cfg(unix)]
+	pub grpc_socket: Option<std::path::PathBuf>,
+	/// Serve HTTP on this Unix domain socket instead of `address`:`http_port`.
+	#[cfg(all(unix, feature = "http"))]
+	pub http_socket: Option<std::path::PathBuf>,
+	/// Permissions for the Unix domain sockets, in octal, e.g. `660`.
+	#[cfg(unix)]
+	#[serde(with = "octal")]
+	pub socket_mode: Option<u32>,
+
+	/// How long, in seconds, to wait for in-flight requests to drain on shutdown.
+	pub shutdown_timeout: u64,
+
+	/// Largest gRPC message, in bytes, services accept; tonic defaults to 4 MiB.
+	pub grpc_max_decoding_message_size: Option<usize>,
+	/// Largest gRPC message, in bytes, services send.
+	pub grpc_max_encoding_message_size: Option<usize>,
+	/// Encodings gRPC services accept and compress responses with, e.g. `[gzip, zstd]`.
+	#[cfg(feature = "compression")]
+	pub grpc_compression: Vec<crate::grpc::Compression>,
+	/// Interval, in seconds, between HTTP/2 keepalive pings on gRPC connections.
+	pub grpc_keepalive_interval: Option<u64>,
+	/// How long, in seconds, to wait for a keepalive ping to be acknowledged.
+	pub grpc_keepalive_timeout: Option<u64>,
+	/// Maximum number of concurrent requests on each gRPC connection.
+	pub grpc_concurrency_limit: Option<usize>,
+	/// How long, in seconds, a gRPC request may take.
+	pub grpc_timeout: Option<u64>,
+	/// Whether to serve gRPC reflection (v1 and v1alpha); defaults to on in debug builds only.
+	#[cfg(feature = "reflection")]
+	pub grpc_reflection: Option<bool>,
+
+	/// PEM certificate chain for the gRPC and HTTP listeners; TLS is enabled when this and
+	/// `tls_key` are set.
+	#[cfg(feature = "tls")]
+	pub tls_cert: Option<std::path::PathBuf>,
+	/// PEM private key for `tls_cert`.
+	#[cfg(feature = "tls")]
+	pub tls_key: Option<std::path::PathBuf>,
+	/// PEM CA bundle client certificates must chain to; when set, clients must use mutual TLS.
+	#[cfg(feature = "tls")]
+	pub tls_client_ca: Option<std::path::PathBuf>,
+	/// How often, in seconds, to check the TLS files for rotation; `0` disables reloading.
+	#[cfg(feature = "tls")]
+	pub tls_reload_interval: u64,
+
+	/// How often, in seconds, to check the config file for changes; `0`, the default, only
+	/// reloads on `SIGHUP`.
+	pub config_reload_interval: u64,
+
+	/// Log filter directives used instead of `RUST_LOG`, e.g. `info,billing=debug`; unlike other
+	/// settings, a change is applied on [`reload`].
+	pub log_filter: Option<String>,
+
 	#[cfg(feature = "redis")]
 	pub redis_url: Url,
 
@@ -34,7 +130,58 @@ impl Default for Config {
 			grpc_port: 50051,
 			#[cfg(feature = "http")]
 			http_port: 3434,
+			#[cfg(feature = "http")]
+			single_port: false,
+			#[cfg(feature = "http")]
+			http_body_limit: None,
+			#[cfg(feature = "http")]
+			http_timeout: None,
+			#[cfg(feature = "http")]
+			http_cors_origins: Vec::new(),
+			#[cfg(all(feature = "http", feature = "compression"))]
+			http_compression: false,
+			#[cfg(feature = "http")]
+			http_health_probes: false,
+			#[cfg(feature = "http")]
+			http_trace_headers: false,
+			#[cfg(feature = "http")]
+			http_sensitive_headers: [
+				"authorization",
+				"proxy-authorization",
+				"cookie",
+				"set-cookie",
+			]
+			.map(String::from)
+			.to_vec(),
 			address: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+			admin_port: None,
+			#[cfg(unix)]
+			grpc_socket: None,
+			#[cfg(all(unix, feature = "http"))]
+			http_socket: None,
+			#[cfg(unix)]
+			socket_mode: None,
+			shutdown_timeout: 30,
+			grpc_max_decoding_message_size: None,
+			grpc_max_encoding_message_size: None,
+			#[cfg(feature = "compression")]
+			grpc_compression: Vec::new(),
+			grpc_keepalive_interval: None,
+			grpc_keepalive_timeout: None,
+			grpc_concurrency_limit: None,
+			grpc_timeout: None,
+			#[cfg(feature = "reflection")]
+			grpc_reflection: None,
+			#[cfg(feature = "tls")]
+			tls_cert: None,
+			#[cfg(feature = "tls")]
+			tls_key: None,
+			#[cfg(feature = "tls")]
+			tls_client_ca: None,
+			#[cfg(feature = "tls")]
+			tls_reload_interval: 60,
+			config_reload_interval: 0,
+			log_filter: None,
 			#[cfg(feature = "redis")]
 			redis_url: Url::parse("redis://valkey/").expect("Hardcoded Redis URL"),
 			#[cfg(feature = "db")]
@@ -43,43 +190,872 @@ impl Default for Config {
 	}
 }
 
-impl Config {
-	// Allow the configuration to be extracted from any `Provider`.
-	fn from<T: Provider>(provider: T) -> Result<Config, figment::Error> {
-		Figment::from(provider).extract()
+/// Checks on settings beyond what deserialization enforces
+pub trait Validate {
+	/// Add a message for every problem to `problems`, e.g. ``"`grpc_port`: must not be 0"``
+	///
+	/// By default there are no checks.
+	fn validate(&self, problems: &mut Vec<String>) {
+		let _ = problems;
+	}
+}
+
+/// Settings that are ignored, e.g. for a [`Module`](crate::module::Module) without any.
+impl Validate for serde::de::IgnoredAny {}
+
+impl Validate for Config {
+	fn validate(&self, problems: &mut Vec<String>) {
+		if self.admin_port == Some(0) {
+			problems.push("`admin_port`: must not be 0".to_string());
+		}
+		if let Some(Err(err)) = self.log_filter.as_deref().map(crate::tracing::parse_filter) {
+			problems.push(format!("`log_filter`: {err}"));
+		}
+		#[cfg(feature = "redis")]
+		check_scheme(
+			problems,
+			"redis_url",
+			&self.redis_url,
+			&["redis", "rediss", "redis+unix", "unix"],
+		);
+		#[cfg(feature = "db")]
+		if let Some(url) = &self.postgres_url {
+			check_scheme(problems, "postgres_url", url, &["postgres", "postgresql"]);
+		}
+	}
+}
+
+#[cfg(any(feature = "redis", feature = "db"))]
+fn check_scheme(problems: &mut Vec<String>, key: &str, url: &Url, schemes: &[&str]) {
+	if !schemes.contains(&url.scheme()) {
+		problems.push(format!(
+			"`{key}`: scheme must be one of {}, found `{}`",
+			schemes.join(", "),
+			url.scheme()
+		));
 	}
+}
 
-	// Provide a default provider, a `Figment`.
-	fn figment() -> Figment {
-		use figment::providers::Env;
+/// Where a configuration source comes from
+#[derive(Debug, Clone)]
+enum Origin {
+	Defaults,
+	File(PathBuf),
+	/// The table of a config file named after the environment.
+	Profile(PathBuf, String),
+	Env(&'static EnvNaming),
+	/// Stand-ins for missing values, so extraction can go on to report further problems.
+	Placeholder,
+}
 
-		Figment::from(Config::default()).merge(Env::raw())
+impl Origin {
+	/// Where the value at `path` was set, for error messages.
+	fn describe(&self, path: &[String]) -> String {
+		match self {
+			Origin::Defaults => "defaults".to_string(),
+			Origin::File(file) => format!("config file `{}`", file.display()),
+			Origin::Profile(file, env) => {
+				format!("`[{env}]` in config file `{}`", file.display())
+			}
+			Origin::Env(naming) => format!(
+				"env var `{}{}`",
+				naming.prefix,
+				path.join(&naming.separator).to_uppercase()
+			),
+			Origin::Placeholder => "placeholders".to_string(),
+		}
 	}
 }
 
+/// A configuration source, read once.
+#[derive(Debug, Clone)]
+struct Source {
+	origin: Origin,
+	data: std::result::Result<Map<Profile, Dict>, figment::Error>,
+}
+
+impl Source {
+	/// Drop the value at `path`, returning whether there was one.
+	fn remove(&mut self, path: &[String]) -> bool {
+		let (Ok(data), Some((last, parents))) = (&mut self.data, path.split_last()) else {
+			return false;
+		};
+		data.values_mut().any(|dict| {
+			let mut dict = dict;
+			for key in parents {
+				dict = match dict.get_mut(key) {
+					Some(Value::Dict(_, nested)) => nested,
+					_ => return false,
+				};
+			}
+			dict.remove(last).is_some()
+		})
+	}
+
+	/// Set `path` to `value`, creating the tables above it.
+	fn insert(&mut self, path: &[String], value: Value) {
+		let (Ok(data), Some((last, parents))) = (&mut self.data, path.split_last()) else {
+			return;
+		};
+		let mut dict = data.entry(Profile::Default).or_default();
+		for key in parents {
+			let entry = dict
+				.entry(key.clone())
+				.or_insert_with(|| Value::from(Dict::new()));
+			if !matches!(entry, Value::Dict(..)) {
+				*entry = Value::from(Dict::new());
+			}
+			let Value::Dict(_, nested) = entry else {
+				unreachable!("replaced with a table above");
+			};
+			dict = nested;
+		}
+		dict.insert(last.clone(), value);
+	}
+}
+
+/// The values tried in turn in place of a missing one, until one has the expected type.
+fn placeholder(attempt: usize) -> Option<Value> {
+	Some(match attempt {
+		0 => Value::from(Dict::new()),
+		1 => Value::from(""),
+		2 => Value::from(0),
+		3 => Value::from(false),
+		4 => Value::from(Vec::<Value>::new()),
+		_ => return None,
+	})
+}
+
+impl Provider for Source {
+	fn metadata(&self) -> Metadata {
+		Metadata::named(self.origin.describe(&[]))
+	}
+
+	fn data(&self) -> std::result::Result<Map<Profile, Dict>, figment::Error> {
+		self.data.clone()
+	}
+}
+
+/// The configuration sources in the order documented on [`config`].
+fn sources() -> Vec<Source> {
+	read_sources(config_file(), &environment(), env_naming())
+}
+
+/// Read the defaults, the config file `file` with its `env` table, and the environment.
+fn read_sources(file: Option<&Path>, env: &str, naming: &'static EnvNaming) -> Vec<Source> {
+	use figment::providers::{Env, Format, Toml};
+
+	let mut sources = vec![Source {
+		origin: Origin::Defaults,
+		data: Config::default().data(),
+	}];
+	if let Some(path) = file {
+		let data = Toml::file_exact(path).data();
+		// A file that cannot be read is reported once, by the file source.
+		let profile = data.as_ref().map_or_else(
+			|_| Map::new(),
+			|data| {
+				data.iter()
+					.filter_map(|(profile, dict)| {
+						Some((profile.clone(), dict.get(env)?.clone().into_dict()?))
+					})
+					.collect()
+			},
+		);
+		sources.push(Source {
+			origin: Origin::File(path.to_path_buf()),
+			data,
+		});
+		sources.push(Source {
+			origin: Origin::Profile(path.to_path_buf(), env.to_string()),
+			data: Ok(profile),
+		});
+	}
+	sources.push(Source {
+		origin: Origin::Env(naming),
+		data: Env::prefixed(&naming.prefix)
+			.split(naming.separator.as_str())
+			.data(),
+	});
+	sources
+}
+
+/// The configuration sources as first read, which [`extract`] and [`config`] keep using.
+static SOURCES: LazyLock<Arc<[Source]>> = LazyLock::new(|| sources().into());
+
+/// The configuration sources as last reloaded, if they have been.
+static RELOADED: RwLock<Option<Arc<[Source]>>> = RwLock::new(None);
+
+/// The configuration sources [`watch`] extracts from: the reloaded ones, if any.
+fn current_sources() -> Arc<[Source]> {
+	RELOADED
+		.read()
+		.expect("config sources lock poisoned")
+		.clone()
+		.unwrap_or_else(|| SOURCES.clone())
+}
+
+/// Extract `T` from `sources`, or from their `section` table, reporting every invalid value
+/// rather than only the first
+///
+/// Each invalid value is dropped from its source and extraction retried, so a lower source or
+/// the serde default can fill in. Serde reports one missing field at a time, so each is filled
+/// with a placeholder and extraction retried; fields missing below a missing table are not
+/// reported again. Stops early if no placeholder has the type a missing field expects.
+fn extract_from<T: DeserializeOwned>(
+	sources: &[Source],
+	section: Option<&str>,
+) -> std::result::Result<T, Vec<String>> {
+	let mut sources = sources.to_vec();
+	let mut problems = Vec::new();
+	for source in &mut sources {
+		if let Err(err) = &source.data {
+			problems.push(format!(
+				"cannot read {}: {}",
+				source.origin.describe(&[]),
+				err.kind.to_string().trim_end()
+			));
+			source.data = Ok(Map::new());
+		}
+	}
+
+	let mut dropped = Vec::new();
+	let mut placeholders = Source {
+		origin: Origin::Placeholder,
+		data: Ok(Map::new()),
+	};
+	let mut attempts: Vec<(Vec<String>, usize)> = Vec::new();
+	loop {
+		let figment = sources
+			.iter()
+			.chain([&placeholders])
+			.fold(Figment::new(), Figment::merge);
+		let figment = match section {
+			Some(key) => figment.focus(key),
+			None => figment,
+		};
+		let err = match figment.extract() {
+			Ok(value) if problems.is_empty() => return Ok(value),
+			Ok(_) => break,
+			Err(err) => err,
+		};
+		let mut path: Vec<String> = section
+			.map(String::from)
+			.into_iter()
+			.chain(err.path.iter().cloned())
+			.collect();
+		if let Kind::MissingField(field) = &err.kind {
+			path.push(field.to_string());
+			// A value dropped for being invalid has already been reported, and so has a missing
+			// table above this field.
+			if !dropped.contains(&path) && !attempts.iter().any(|(p, _)| path.starts_with(p)) {
+				problems.push(format!("`{}`: missing", path.join(".")));
+			}
+			placeholders.insert(&path, placeholder(0).expect("first placeholder"));
+			attempts.push((path, 0));
+			continue;
+		}
+		if let Some((_, attempt)) = attempts.iter_mut().find(|(p, _)| *p == path) {
+			*attempt += 1;
+			match placeholder(*attempt) {
+				Some(value) => {
+					placeholders.insert(&path, value);
+					continue;
+				}
+				None => break,
+			}
+		}
+
+		let name = err.metadata.as_ref().map(|metadata| &metadata.name);
+		let Some(source) = sources
+			.iter_mut()
+			.find(|source| Some(&source.metadata().name) == name)
+			.and_then(|source| source.remove(&path).then_some(source))
+		else {
+			problems.push(err.to_string());
+			break;
+		};
+		problems.push(format!(
+			"`{}`: {} (from {})",
+			path.join("."),
+			err.kind,
+			source.origin.describe(&path)
+		));
+		dropped.push(path);
+	}
+	Err(problems)
+}
+
+/// [`extract_from`] and check the result with [`Validate`].
+fn extract_validated_from<T: DeserializeOwned + Validate>(
+	sources: &[Source],
+	section: Option<&str>,
+) -> std::result::Result<T, Vec<String>> {
+	let value: T = extract_from(sources, section)?;
+	let mut problems = Vec::new();
+	value.validate(&mut problems);
+	if problems.is_empty() {
+		Ok(value)
+	} else {
+		Err(problems)
+	}
+}
+
+fn invalid(problems: &[String]) -> Error {
+	Error::Config(format!(
+		"invalid configuration:\n- {}",
+		problems.join("\n- ")
+	))
+}
+
+/// How environment variables map to config keys
+///
+/// Only variables starting with `prefix` are read. The rest of the name, lowercased, is the key,
+/// with `separator` descending into nested tables: with prefix `BILLING_` and separator `__`,
+/// `BILLING_GRPC_PORT` sets `grpc_port` and `BILLING_AUTH__ISSUER` sets `auth.issuer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvNaming {
+	pub prefix: String,
+	pub separator: String,
+}
+
+impl EnvNaming {
+	/// The package name in upper snake case followed by `_`, e.g. `BILLING_API_` for
+	/// `billing-api`, with `__` as the separator
+	#[must_use]
+	pub fn from_pkg(pkg: &str) -> Self {
+		let mut prefix: String = pkg
+			.chars()
+			.map(|c| {
+				if c.is_ascii_alphanumeric() {
+					c.to_ascii_uppercase()
+				} else {
+					'_'
+				}
+			})
+			.collect();
+		prefix.push('_');
+		Self {
+			prefix,
+			separator: "__".to_string(),
+		}
+	}
+}
+
+/// The env naming in use, and whether it was chosen with [`set_env_naming`].
+#[derive(Debug)]
+struct ChosenNaming {
+	naming: EnvNaming,
+	explicit: bool,
+}
+
+static ENV_NAMING: OnceLock<ChosenNaming> = OnceLock::new();
+
+/// Set how environment variables are named
+///
+/// This must happen before the configuration is first loaded. The
+/// [`ServiceBuilder`](crate::service::ServiceBuilder) derives the naming from
+/// [`ServiceInfo::pkg`](crate::ServiceInfo::pkg) unless it was set before.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if the naming was already set or used.
+pub fn set_env_naming(naming: EnvNaming) -> Result<()> {
+	ENV_NAMING
+		.set(ChosenNaming {
+			naming,
+			explicit: true,
+		})
+		.map_err(|_| Error::Config("env naming already set".to_string()))
+}
+
+/// Use `naming` unless another was set with [`set_env_naming`]
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if a different naming was already derived from the executable,
+/// because the configuration was read before the service was built.
+pub(crate) fn default_env_naming(naming: &EnvNaming) -> Result<()> {
+	choose_default(&ENV_NAMING, naming)
+}
+
+fn choose_default(chosen: &OnceLock<ChosenNaming>, naming: &EnvNaming) -> Result<()> {
+	let current = chosen.get_or_init(|| ChosenNaming {
+		naming: naming.clone(),
+		explicit: false,
+	});
+	if current.explicit || current.naming == *naming {
+		return Ok(());
+	}
+	Err(Error::Config(format!(
+		"the configuration was read from `{}*` env vars before the service was built, instead \
+		 of `{}*`; call `config::set_env_naming` before reading it",
+		current.naming.prefix, naming.prefix
+	)))
+}
+
+/// How environment variables are named
+///
+/// If the naming was not set before the configuration was loaded, it is derived from the name of
+/// the executable.
+pub fn env_naming() -> &'static EnvNaming {
+	&ENV_NAMING
+		.get_or_init(|| {
+			let exe = std::env::current_exe().ok();
+			let name = exe
+				.as_deref()
+				.and_then(|exe| exe.file_stem())
+				.and_then(|stem| stem.to_str())
+				.unwrap_or("runesys");
+			ChosenNaming {
+				naming: EnvNaming::from_pkg(name),
+				explicit: false,
+			}
+		})
+		.naming
+}
+
+/// The deployment environment, from `ENVIRONMENT`
+///
+/// Defaults to `development` in debug builds and `production` otherwise.
+#[must_use]
+pub fn environment() -> String {
+	std::env::var("ENVIRONMENT").unwrap_or_else(|_| {
+		if cfg!(debug_assertions) {
+			"development".to_string()
+		} else {
+			"production".to_string()
+		}
+	})
+}
+
+static CONFIG_FILE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Read the configuration from the TOML file at `path`
+///
+/// This must happen before the configuration is first loaded, and takes precedence over
+/// `<prefix>CONFIG_FILE`.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if the config file was already set or used.
+pub fn set_config_file(path: impl Into<PathBuf>) -> Result<()> {
+	CONFIG_FILE
+		.set(Some(path.into()))
+		.map_err(|_| Error::Config("config file already set".to_string()))
+}
+
+/// The TOML config file, from [`set_config_file`] or else `<prefix>CONFIG_FILE`
+///
+/// Without either, no file is read; a file that was named but cannot be read is an error. The
+/// file is chosen once, so reloads read the same one.
+#[must_use]
+pub fn config_file() -> Option<&'static Path> {
+	CONFIG_FILE
+		.get_or_init(|| {
+			std::env::var_os(format!("{}CONFIG_FILE", env_naming().prefix)).map(PathBuf::from)
+		})
+		.as_deref()
+}
+
 impl Provider for Config {
 	fn metadata(&self) -> Metadata {
 		Metadata::named("runesys Config")
 	}
 
-	fn data(&self) -> Result<Map<Profile, Dict>, figment::Error> {
+	fn data(&self) -> std::result::Result<Map<Profile, Dict>, figment::Error> {
 		figment::providers::Serialized::defaults(Config::default()).data()
 	}
 }
 
-pub static FIGMENT: LazyLock<Figment> = LazyLock::new(|| Config::figment());
+/// (De)serialize file permissions as octal, from either a string or the digits of an integer.
+#[cfg(unix)]
+mod octal {
+	use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Mode {
+		Int(u32),
+		Str(String),
+	}
 
+	#[allow(clippy::ref_option, clippy::trivially_copy_pass_by_ref)]
+	pub fn serialize<S: Serializer>(mode: &Option<u32>, s: S) -> Result<S::Ok, S::Error> {
+		match mode {
+			Some(mode) => s.serialize_str(&format!("{mode:o}")),
+			None => s.serialize_none(),
+		}
+	}
+
+	pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+		let Some(mode) = Option::<Mode>::deserialize(d)? else {
+			return Ok(None);
+		};
+		let digits = match mode {
+			Mode::Int(n) => n.to_string(),
+			Mode::Str(s) => s,
+		};
+		let digits = digits.trim_start_matches("0o");
+		u32::from_str_radix(digits, 8)
+			.map(Some)
+			.map_err(|_| D::Error::custom(format!("invalid octal mode `{digits}`")))
+	}
+}
+
+/// Every configuration source merged, in the order documented on [`config`], as first read
+pub static FIGMENT: LazyLock<Figment> =
+	LazyLock::new(|| SOURCES.iter().fold(Figment::new(), Figment::merge));
+
+/// Define `load_config()` and `config()` accessors for settings of type `$ty`, read from the same
+/// sources as [`Config`]
+///
+/// `load_config()` returns the error of [`extract`], and `config()` panics with it. With
+/// `define_config!($ty, validate)`, the settings are also checked with [`Validate`].
 #[macro_export]
 macro_rules! define_config {
 	($ty:ty) => {
+		::runesys::define_config!(@accessors $ty, extract);
+	};
+	($ty:ty, validate) => {
+		::runesys::define_config!(@accessors $ty, extract_validated);
+	};
+	(@accessors $ty:ty, $extract:ident) => {
+		/// # Errors
+		///
+		/// Returns a config error listing every problem if the settings are invalid.
+		pub fn load_config() -> ::std::result::Result<&'static $ty, ::runesys::error::Error> {
+			static CONFIG: ::std::sync::OnceLock<$ty> = ::std::sync::OnceLock::new();
+			if let Some(config) = CONFIG.get() {
+				return Ok(config);
+			}
+			let config = ::runesys::config::$extract()?;
+			Ok(CONFIG.get_or_init(|| config))
+		}
+
+		/// # Panics
+		///
+		/// Panics if the settings are invalid.
 		pub fn config() -> &'static $ty {
-			pub static CONFIG: std::sync::OnceLock<$ty> = std::sync::OnceLock::new();
-			CONFIG.get_or_init(|| ::runesys::config::FIGMENT.extract().unwrap())
+			load_config().unwrap_or_else(|err| panic!("{err}"))
 		}
 	};
 }
 
+/// Extract settings of type `T` from the same sources as [`Config`]
+///
+/// [`define_config!`] types are layered the same way. Nested fields are set from the environment
+/// with the [`EnvNaming`] separator, e.g. `auth.issuer` as `<prefix>AUTH__ISSUER`; fields the
+/// sources do not set need a serde default.
+///
+/// The sources are those read at startup, even after a [`reload`], so settings extracted at any
+/// time agree with [`config`]; use [`watch`] to follow reloads.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] listing every invalid value with the source that set it, or the
+/// first missing field.
+pub fn extract<T: DeserializeOwned>() -> Result<T> {
+	extract_from(&SOURCES, None).map_err(|problems| invalid(&problems))
+}
+
+/// [`extract`] settings of type `T` and check them with [`Validate`]
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] listing every problem found by [`extract`] or [`Validate`].
+pub fn extract_validated<T: DeserializeOwned + Validate>() -> Result<T> {
+	extract_validated_from(&SOURCES, None).map_err(|problems| invalid(&problems))
+}
+
+/// [`extract_validated`] settings of type `T` from the table `key`, e.g. `[auth]` in the config
+/// file or `<prefix>AUTH__ISSUER`
+///
+/// A missing table is treated as empty, so `T` must then be satisfied by serde defaults.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] listing every problem found by [`extract`] or [`Validate`], with
+/// paths starting at `key`.
+pub fn extract_section<T: DeserializeOwned + Validate>(key: &str) -> Result<T> {
+	extract_validated_from(&SOURCES, Some(key)).map_err(|problems| invalid(&problems))
+}
+
+/// Load and validate the runesys configuration, as [`config`] does without panicking
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] listing every problem with the configuration.
+pub fn load() -> Result<&'static Config> {
+	static CONFIG: OnceLock<Config> = OnceLock::new();
+	if let Some(config) = CONFIG.get() {
+		return Ok(config);
+	}
+	let config = extract_validated()?;
+	Ok(CONFIG.get_or_init(|| config))
+}
+
+/// The runesys configuration, loaded on first use
+///
+/// Sources are layered from lowest to highest precedence:
+///
+/// 1. [`Config::default()`]
+/// 2. the top level of the TOML file from [`config_file`], if any
+/// 3. the table of that file named after the [`environment`], e.g. `[production]`
+/// 4. environment variables named as described by [`EnvNaming`], e.g. `BILLING_GRPC_PORT`
+///
+/// # Panics
+///
+/// Panics with the error of [`load`] if the configuration is invalid, or a named config file
+/// cannot be read.
+#[must_use]
 pub fn config() -> &'static Config {
-	pub static CONFIG: OnceLock<Config> = OnceLock::new();
-	CONFIG.get_or_init(|| FIGMENT.extract().unwrap())
+	load().unwrap_or_else(|err| panic!("{err}"))
+}
+
+#[cfg(test)]
+mod tests {
+	use figment::providers::{Format, Toml};
+	use serde::Deserialize;
+
+	use super::*;
+
+	fn file(name: &str, toml: &str) -> Source {
+		Source {
+			origin: Origin::File(name.into()),
+			data: Toml::string(toml).data(),
+		}
+	}
+
+	/// A config file in the temporary directory, unique to this process and test.
+	fn temp_file(name: &str, toml: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("runesys-{}-{name}.toml", std::process::id()));
+		std::fs::write(&path, toml).unwrap();
+		path
+	}
+
+	fn naming(prefix: &str) -> &'static EnvNaming {
+		Box::leak(Box::new(EnvNaming {
+			prefix: prefix.to_string(),
+			separator: "__".to_string(),
+		}))
+	}
+
+	/// Run `f` with the environment variables `vars` set.
+	fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+		let _env = crate::ENV_LOCK
+			.lock()
+			.unwrap_or_else(std::sync::PoisonError::into_inner);
+		// SAFETY: environment changes in tests are serialised by ENV_LOCK.
+		unsafe {
+			for (key, value) in vars {
+				std::env::set_var(key, value);
+			}
+		}
+		let res = f();
+		// SAFETY: as above.
+		unsafe {
+			for (key, _) in vars {
+				std::env::remove_var(key);
+			}
+		}
+		res
+	}
+
+	#[test]
+	fn layers_defaults_file_environment_table_and_env_vars() {
+		let file = temp_file(
+			"layers",
+			"grpc_port = 1\nshutdown_timeout = 2\nconfig_reload_interval = 3\n\n\
+			 [production]\nshutdown_timeout = 20\nconfig_reload_interval = 30\n\n\
+			 [staging]\ngrpc_port = 100",
+		);
+		let sources = with_env(&[("LAYERS_CONFIG_RELOAD_INTERVAL", "300")], || {
+			read_sources(Some(&file), "production", naming("LAYERS_"))
+		});
+		let config: Config = extract_from(&sources, None).unwrap();
+
+		assert_eq!(config.grpc_port, 1);
+		assert_eq!(config.shutdown_timeout, 20);
+		assert_eq!(config.config_reload_interval, 300);
+		assert_eq!(config.admin_port, Config::default().admin_port);
+		let _ = std::fs::remove_file(file);
+	}
+
+	#[test]
+	fn reads_nested_env_vars() {
+		let sources = with_env(
+			&[
+				("NESTED_AUTH__ISSUER", "env"),
+				("NESTED_AUTH__LEEWAY", "7"),
+				("OTHER_AUTH__LEEWAY", "9"),
+			],
+			|| read_sources(None, "production", naming("NESTED_")),
+		);
+		let auth: Auth = extract_validated_from(&sources, Some("auth")).unwrap();
+		assert_eq!(
+			auth,
+			Auth {
+				issuer: "env".into(),
+				leeway: 7
+			}
+		);
+	}
+
+	#[test]
+	fn names_env_vars_after_the_package() {
+		let naming = EnvNaming::from_pkg("billing-api.v2");
+		assert_eq!(naming.prefix, "BILLING_API_V2_");
+		assert_eq!(naming.separator, "__");
+	}
+
+	#[test]
+	fn keeps_an_explicit_naming_but_rejects_a_derived_one() {
+		let explicit = OnceLock::new();
+		explicit
+			.set(ChosenNaming {
+				naming: EnvNaming::from_pkg("custom"),
+				explicit: true,
+			})
+			.unwrap();
+		assert!(choose_default(&explicit, &EnvNaming::from_pkg("billing")).is_ok());
+		assert_eq!(explicit.get().unwrap().naming.prefix, "CUSTOM_");
+
+		let unset = OnceLock::new();
+		assert!(choose_default(&unset, &EnvNaming::from_pkg("billing")).is_ok());
+		assert_eq!(unset.get().unwrap().naming.prefix, "BILLING_");
+
+		let derived = OnceLock::new();
+		derived
+			.set(ChosenNaming {
+				naming: EnvNaming::from_pkg("billing-server"),
+				explicit: false,
+			})
+			.unwrap();
+		assert!(choose_default(&derived, &EnvNaming::from_pkg("billing-server")).is_ok());
+		assert!(choose_default(&derived, &EnvNaming::from_pkg("billing")).is_err());
+	}
+
+	#[test]
+	fn reports_a_missing_config_file() {
+		let path = std::env::temp_dir().join("runesys-missing.toml");
+		let sources = read_sources(Some(&path), "production", naming("MISSING_"));
+		let problems = extract_from::<Config>(&sources, None).unwrap_err();
+		assert_eq!(problems.len(), 1);
+		assert!(
+			problems[0].starts_with(&format!("cannot read config file `{}`", path.display())),
+			"{problems:?}"
+		);
+	}
+
+	#[derive(Debug, Deserialize)]
+	#[allow(dead_code)]
+	struct Settings {
+		port: u16,
+		name: String,
+		workers: usize,
+		auth: Auth,
+		mode: Mode,
+	}
+
+	#[derive(Debug, Deserialize)]
+	#[serde(rename_all = "lowercase")]
+	enum Mode {
+		Fast,
+	}
+
+	#[test]
+	fn reports_every_invalid_value_with_its_source() {
+		let sources = [
+			file("a.toml", "port = 1\nname = \"a\"\nworkers = 2"),
+			file(
+				"b.toml",
+				"port = \"high\"\nworkers = -1\n[auth]\nissuer = 5",
+			),
+		];
+		let problems = extract_from::<Settings>(&sources, None).unwrap_err();
+		assert_eq!(
+			problems,
+			[
+				"`auth.issuer`: invalid type: found signed int `5`, expected a string (from config \
+				 file `b.toml`)",
+				"`port`: invalid type: found string \"high\", expected u16 (from config file `b.toml`)",
+				"`workers`: invalid value signed int `-1`, expected usize (from config file `b.toml`)",
+				"`mode`: missing",
+			]
+		);
+	}
+
+	#[test]
+	fn reports_every_missing_value_once() {
+		let problems = extract_from::<Settings>(&[file("a.toml", "port = 1")], None).unwrap_err();
+		assert_eq!(
+			problems,
+			[
+				"`name`: missing",
+				"`workers`: missing",
+				"`auth`: missing",
+				"`mode`: missing",
+			]
+		);
+	}
+
+	#[test]
+	fn adds_validation_problems() {
+		let sources = [
+			Source {
+				origin: Origin::Defaults,
+				data: Config::default().data(),
+			},
+			file("a.toml", "admin_port = 0"),
+		];
+		let problems = extract_validated_from::<Config>(&sources, None).unwrap_err();
+		assert_eq!(problems, ["`admin_port`: must not be 0"]);
+
+		let config: Config = extract_validated_from(&sources[..1], None).unwrap();
+		assert_eq!(config.grpc_port, Config::default().grpc_port);
+	}
+
+	#[derive(Debug, Deserialize, PartialEq)]
+	struct Auth {
+		issuer: String,
+		#[serde(default)]
+		leeway: u64,
+	}
+
+	impl Validate for Auth {}
+
+	#[test]
+	fn extracts_a_section() {
+		let sources = [
+			file(
+				"a.toml",
+				"grpc_port = 1\n[auth]\nissuer = \"a\"\nleeway = 5",
+			),
+			file("b.toml", "[auth]\nissuer = \"b\""),
+		];
+		let auth: Auth = extract_validated_from(&sources, Some("auth")).unwrap();
+		assert_eq!(
+			auth,
+			Auth {
+				issuer: "b".into(),
+				leeway: 5
+			}
+		);
+
+		let _: serde::de::IgnoredAny = extract_validated_from(&sources, Some("none")).unwrap();
+	}
+
+	#[test]
+	fn reports_section_problems_with_full_paths() {
+		let sources = [file("a.toml", "[auth]\nleeway = \"soon\"")];
+		let problems = extract_from::<Auth>(&sources, Some("auth")).unwrap_err();
+		assert_eq!(
+			problems,
+			[
+				"`auth.leeway`: invalid type: found string \"soon\", expected u64 (from config file `a.toml`)",
+				"`auth.issuer`: missing",
+			]
+		);
+	}
 }
@@ -0,0 +1,360 @@
+use std::{
+	any::{Any, TypeId},
+	sync::{Arc, Mutex, PoisonError},
+	time::Duration,
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use super::{Config, RELOADED, Source, Validate, current_sources, extract_validated_from, invalid};
+use crate::error::Result;
+
+/// Publishes the new value of a watched type, once every watched type has been extracted.
+type Commit = Box<dyn FnOnce() + Send>;
+type Reload = Box<dyn Fn(&[Source]) -> std::result::Result<Commit, Vec<String>> + Send>;
+
+struct Watched {
+	type_id: TypeId,
+	/// The `watch::Sender<Arc<T>>` receivers subscribe to.
+	sender: Box<dyn Any + Send>,
+	reload: Reload,
+}
+
+impl Watched {
+	fn new<T>(sender: watch::Sender<Arc<T>>) -> Self
+	where
+		T: DeserializeOwned + Validate + PartialEq + Send + Sync + 'static,
+	{
+		let publish = sender.clone();
+		Watched {
+			type_id: TypeId::of::<T>(),
+			sender: Box::new(sender),
+			reload: Box::new(move |sources| {
+				let value: T = extract_validated_from(sources, None)?;
+				let publish = publish.clone();
+				Ok(Box::new(move || {
+					publish.send_if_modified(|current| {
+						let modified = **current != value;
+						if modified {
+							*current = Arc::new(value);
+						}
+						modified
+					});
+				}))
+			}),
+		}
+	}
+}
+
+static WATCHED: Mutex<Vec<Watched>> = Mutex::new(Vec::new());
+
+/// Top-level [`Config`] fields that [`reload`] applies; changing any other needs a restart.
+const RELOADABLE: &[&str] = &["log_filter"];
+
+/// Settings of type `T`, updated on every successful [`reload`]
+///
+/// `T` is extracted and validated like [`extract_validated`](super::extract_validated), but from
+/// the sources as last reloaded. Receivers for the same type share a channel, and are only
+/// notified when a reload changed their settings.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`](crate::error::Error::Config) listing every problem if `T` cannot be
+/// extracted from the current sources.
+pub fn watch<T>() -> Result<watch::Receiver<Arc<T>>>
+where
+	T: DeserializeOwned + Validate + PartialEq + Send + Sync + 'static,
+{
+	let mut watched = WATCHED.lock().unwrap_or_else(PoisonError::into_inner);
+	if let Some(sender) = watched
+		.iter()
+		.find(|watched| watched.type_id == TypeId::of::<T>())
+		.and_then(|watched| watched.sender.downcast_ref::<watch::Sender<Arc<T>>>())
+	{
+		return Ok(sender.subscribe());
+	}
+
+	let value: T = extract_validated_from(&current_sources(), None).map_err(|p| invalid(&p))?;
+	let (sender, receiver) = watch::channel(Arc::new(value));
+	watched.push(Watched::new(sender));
+	Ok(receiver)
+}
+
+/// Re-read the configuration sources and update every type passed to [`watch`](watch())
+///
+/// Nothing is updated unless every watched type is still valid. Of runesys' own [`Config`], only
+/// the [`log_filter`](Config::log_filter) is applied; the service keeps running with the other
+/// settings from startup, and the ones that changed are returned and logged as requiring a
+/// restart. [`config`](super::config), [`extract`](super::extract) and `load_config()` keep
+/// returning the settings from startup; only [`watch`](watch()) receivers see the new values.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`](crate::error::Error::Config) listing every problem with the new
+/// sources.
+pub fn reload() -> Result<Vec<String>> {
+	let config = watch::<Config>()?;
+	let before = serde_json::to_value(&**config.borrow()).unwrap_or_default();
+
+	let sources = super::sources();
+	{
+		let watched = WATCHED.lock().unwrap_or_else(PoisonError::into_inner);
+		let commits = extract_all(&watched, &sources).map_err(|problems| invalid(&problems))?;
+		*RELOADED.write().unwrap_or_else(PoisonError::into_inner) = Some(sources.into());
+		for commit in commits {
+			commit();
+		}
+	}
+
+	let after = serde_json::to_value(&**config.borrow()).unwrap_or_default();
+	let (applied, restart): (Vec<_>, Vec<_>) = changed(&before, &after)
+		.into_iter()
+		.partition(|key| RELOADABLE.contains(&key.as_str()));
+	if !applied.is_empty() {
+		crate::tracing::set_log_filter(config.borrow().log_filter.as_deref());
+	}
+	for key in &applied {
+		info!("Config `{key}` changed, applied it");
+	}
+	for key in &restart {
+		warn!("Config `{key}` changed, restart to apply it");
+	}
+	Ok(restart)
+}
+
+/// Extract every watched type from `sources`, returning how to publish them, or every distinct
+/// problem if any type is invalid.
+fn extract_all(
+	watched: &[Watched],
+	sources: &[Source],
+) -> std::result::Result<Vec<Commit>, Vec<String>> {
+	let mut commits = Vec::new();
+	let mut problems = Vec::<String>::new();
+	for watched in watched {
+		match (watched.reload)(sources) {
+			Ok(commit) => commits.push(commit),
+			Err(errs) => {
+				for problem in errs {
+					if !problems.contains(&problem) {
+						problems.push(problem);
+					}
+				}
+			}
+		}
+	}
+	if problems.is_empty() {
+		Ok(commits)
+	} else {
+		Err(problems)
+	}
+}
+
+/// The top-level keys whose values differ between `before` and `after`.
+fn changed(before: &Value, after: &Value) -> Vec<String> {
+	let (Value::Object(before), Value::Object(after)) = (before, after) else {
+		return Vec::new();
+	};
+	after
+		.iter()
+		.filter(|(key, value)| before.get(*key) != Some(value))
+		.map(|(key, _)| key.clone())
+		.collect()
+}
+
+/// Reload the configuration on `SIGHUP`, and when the config file changes if
+/// [`config_reload_interval`](Config::config_reload_interval) is set, until `shutdown` is cancelled.
+pub(crate) fn spawn_watcher(shutdown: CancellationToken) {
+	let interval = Duration::from_secs(super::config().config_reload_interval);
+	let polling = super::config_file().filter(|_| !interval.is_zero());
+	#[cfg(not(unix))]
+	if polling.is_none() {
+		return;
+	}
+
+	tokio::spawn(async move {
+		let mut hangup = hangups();
+		let mut contents = polling.and_then(|file| std::fs::read(file).ok());
+		let mut ticker = polling.map(|_| {
+			let mut ticker = tokio::time::interval(interval);
+			ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+			ticker
+		});
+
+		loop {
+			tokio::select! {
+				() = shutdown.cancelled() => return,
+				() = next_hangup(&mut hangup) => info!("SIGHUP received, reloading configuration"),
+				() = tick(&mut ticker) => {
+					let current = polling.and_then(|file| std::fs::read(file).ok());
+					if current == contents {
+						continue;
+					}
+					contents = current;
+					info!("Config file changed, reloading configuration");
+				}
+			}
+
+			match reload() {
+				Ok(_) => info!("Reloaded configuration"),
+				Err(err) => error!("Failed to reload configuration: {err}"),
+			}
+		}
+	});
+}
+
+#[cfg(unix)]
+type Hangups = Option<tokio::signal::unix::Signal>;
+#[cfg(not(unix))]
+type Hangups = ();
+
+#[cfg(unix)]
+fn hangups() -> Hangups {
+	use tokio::signal::unix::{SignalKind, signal};
+
+	signal(SignalKind::hangup())
+		.inspect_err(|err| error!("Cannot listen for SIGHUP: {err}"))
+		.ok()
+}
+
+#[cfg(not(unix))]
+fn hangups() -> Hangups {}
+
+/// Resolves on the next `SIGHUP`, or never where it is unavailable.
+#[cfg_attr(not(unix), allow(clippy::unused_async))]
+async fn next_hangup(hangups: &mut Hangups) {
+	#[cfg(unix)]
+	if let Some(signal) = hangups {
+		signal.recv().await;
+		return;
+	}
+	let _ = hangups;
+	std::future::pending::<()>().await;
+}
+
+/// Resolves on the next tick, or never without polling.
+async fn tick(ticker: &mut Option<tokio::time::Interval>) {
+	match ticker {
+		Some(ticker) => {
+			ticker.tick().await;
+		}
+		None => std::future::pending().await,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use figment::{
+		Provider,
+		providers::{Format, Toml},
+	};
+	use serde::Deserialize;
+	use serde_json::json;
+
+	use super::*;
+	use crate::config::Origin;
+
+	#[derive(Debug, Deserialize, PartialEq)]
+	struct Limits {
+		port: u16,
+		rate: u32,
+	}
+
+	impl Validate for Limits {}
+
+	#[derive(Debug, Deserialize, PartialEq)]
+	struct Flags {
+		port: u16,
+		#[serde(default)]
+		beta: bool,
+	}
+
+	impl Validate for Flags {}
+
+	fn file(toml: &str) -> Vec<Source> {
+		vec![Source {
+			origin: Origin::File("a.toml".into()),
+			data: Toml::string(toml).data(),
+		}]
+	}
+
+	/// Watch `T` as extracted from `toml`.
+	fn watched<T>(toml: &str) -> (Watched, watch::Receiver<Arc<T>>)
+	where
+		T: DeserializeOwned + Validate + PartialEq + Send + Sync + 'static,
+	{
+		let value: T = extract_validated_from(&file(toml), None).unwrap();
+		let (sender, receiver) = watch::channel(Arc::new(value));
+		(Watched::new(sender), receiver)
+	}
+
+	#[test]
+	fn only_notifies_receivers_whose_settings_changed() {
+		let (limits, mut limits_rx) = watched::<Limits>("port = 1\nrate = 10");
+		let (flags, mut flags_rx) = watched::<Flags>("port = 1\nrate = 10");
+		limits_rx.mark_unchanged();
+		flags_rx.mark_unchanged();
+
+		let watched = [limits, flags];
+		let commits = extract_all(&watched, &file("port = 1\nrate = 20")).unwrap();
+		for commit in commits {
+			commit();
+		}
+
+		assert!(limits_rx.has_changed().unwrap());
+		assert_eq!(limits_rx.borrow_and_update().rate, 20);
+		assert!(!flags_rx.has_changed().unwrap());
+	}
+
+	#[test]
+	fn publishes_nothing_unless_every_type_is_valid() {
+		let (limits, limits_rx) = watched::<Limits>("port = 1\nrate = 10");
+		let (flags, flags_rx) = watched::<Flags>("port = 1");
+
+		let watched = [limits, flags];
+		let Err(problems) = extract_all(&watched, &file("port = -1\nrate = 20")) else {
+			panic!("an invalid port was accepted");
+		};
+
+		assert_eq!(
+			problems,
+			["`port`: invalid value signed int `-1`, expected u16 (from config file `a.toml`)"]
+		);
+		assert!(!limits_rx.has_changed().unwrap());
+		assert!(!flags_rx.has_changed().unwrap());
+	}
+
+	#[test]
+	fn lists_the_changed_top_level_keys() {
+		let before = json!({ "grpc_port": 1, "log_filter": null, "auth": { "issuer": "a" } });
+		let after = json!({ "grpc_port": 1, "log_filter": "debug", "auth": { "issuer": "b" } });
+		assert_eq!(changed(&before, &after), ["auth", "log_filter"]);
+		assert!(changed(&before, &before).is_empty());
+	}
+
+	#[test]
+	fn reloadable_keys_are_config_fields() {
+		let config = serde_json::to_value(Config::default()).unwrap();
+		let Value::Object(fields) = config else {
+			panic!("config is not a table");
+		};
+		assert!(RELOADABLE.iter().all(|key| fields.contains_key(*key)));
+	}
+
+	#[test]
+	fn rejects_an_invalid_log_filter() {
+		let sources = [
+			Source {
+				origin: Origin::Defaults,
+				data: Config::default().data(),
+			},
+			file("log_filter = \"info,[\"").remove(0),
+		];
+		let problems = extract_validated_from::<Config>(&sources, None).unwrap_err();
+		assert_eq!(problems.len(), 1);
+		assert!(problems[0].starts_with("`log_filter`: "), "{problems:?}");
+	}
+}
@@ -9,8 +9,24 @@ pub enum Error {
 
 	#[error("transport error")]
 	Transport(#[from] tonic::transport::Error),
+	#[cfg(feature = "reflection")]
 	#[error("reflection error")]
 	Reflection(#[from] tonic_reflection::server::Error),
+	#[cfg(feature = "tls")]
+	#[error("tls error")]
+	Tls(#[from] crate::tls::TlsError),
+
+	#[error("task failed")]
+	Join(#[from] tokio::task::JoinError),
+	#[error("background task {name} failed")]
+	Task {
+		name: std::borrow::Cow<'static, str>,
+		#[source]
+		source: Box<Error>,
+	},
+
+	#[error(transparent)]
+	Setup(#[from] crate::setup::SetupError),
 
 	#[error("io error")]
 	Io(#[from] std::io::Error),
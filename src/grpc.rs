@@ -0,0 +1,194 @@
+use std::time::Duration;
+
+#[cfg(feature = "compression")]
+use serde::{Deserialize, Serialize};
+use tonic::{codec::CompressionEncoding, transport::Server};
+
+use crate::config::Config;
+
+/// A compression encoding for gRPC messages
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+	Gzip,
+	Zstd,
+}
+
+#[cfg(feature = "compression")]
+impl From<Compression> for CompressionEncoding {
+	fn from(compression: Compression) -> Self {
+		match compression {
+			Compression::Gzip => Self::Gzip,
+			Compression::Zstd => Self::Zstd,
+		}
+	}
+}
+
+/// Settings for the gRPC server and the services registered on it
+///
+/// Message size limits and compression are set on each generated server, through
+/// [`Service::configure_server`](crate::Service::configure_server) or [`ConfigureServer`]. The
+/// other settings apply to the whole gRPC listener; they cannot be used when gRPC shares a port
+/// with HTTP, and running such a service fails with [`Error::Config`](crate::error::Error::Config).
+#[derive(Debug, Clone, Default)]
+pub struct GrpcOptions {
+	max_decoding_message_size: Option<usize>,
+	max_encoding_message_size: Option<usize>,
+	compression: Vec<CompressionEncoding>,
+	keepalive_interval: Option<Duration>,
+	keepalive_timeout: Option<Duration>,
+	concurrency_limit: Option<usize>,
+	timeout: Option<Duration>,
+}
+
+impl GrpcOptions {
+	/// The options set by the `grpc_*` fields of `config`
+	#[must_use]
+	pub fn from_config(config: &Config) -> Self {
+		Self {
+			max_decoding_message_size: config.grpc_max_decoding_message_size,
+			max_encoding_message_size: config.grpc_max_encoding_message_size,
+			#[cfg(feature = "compression")]
+			compression: config
+				.grpc_compression
+				.iter()
+				.copied()
+				.map(CompressionEncoding::from)
+				.collect(),
+			#[cfg(not(feature = "compression"))]
+			compression: Vec::new(),
+			keepalive_interval: config.grpc_keepalive_interval.map(Duration::from_secs),
+			keepalive_timeout: config.grpc_keepalive_timeout.map(Duration::from_secs),
+			concurrency_limit: config.grpc_concurrency_limit,
+			timeout: config.grpc_timeout.map(Duration::from_secs),
+		}
+	}
+
+	/// Largest message, in bytes, a service accepts; tonic defaults to 4 MiB
+	#[must_use]
+	pub fn max_decoding_message_size(mut self, limit: usize) -> Self {
+		self.max_decoding_message_size = Some(limit);
+		self
+	}
+
+	/// Largest message, in bytes, a service sends; unlimited by default
+	#[must_use]
+	pub fn max_encoding_message_size(mut self, limit: usize) -> Self {
+		self.max_encoding_message_size = Some(limit);
+		self
+	}
+
+	/// Accept requests compressed with `encodings`, and compress responses for clients that
+	/// accept one of them
+	#[cfg(feature = "compression")]
+	#[must_use]
+	pub fn compression(mut self, encodings: impl IntoIterator<Item = Compression>) -> Self {
+		self.compression = encodings
+			.into_iter()
+			.map(CompressionEncoding::from)
+			.collect();
+		self
+	}
+
+	/// Send HTTP/2 keepalive pings every `interval`, closing the connection if one is not
+	/// acknowledged within `timeout`
+	#[must_use]
+	pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+		self.keepalive_interval = Some(interval);
+		self.keepalive_timeout = Some(timeout);
+		self
+	}
+
+	/// Handle at most `limit` requests at once on each connection
+	#[must_use]
+	pub fn concurrency_limit(mut self, limit: usize) -> Self {
+		self.concurrency_limit = Some(limit);
+		self
+	}
+
+	/// Fail requests that take longer than `timeout` with `CANCELLED`
+	///
+	/// A shorter `grpc-timeout` sent by the client takes precedence.
+	#[must_use]
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// The limit set by [`max_decoding_message_size`](Self::max_decoding_message_size)
+	#[must_use]
+	pub fn get_max_decoding_message_size(&self) -> Option<usize> {
+		self.max_decoding_message_size
+	}
+
+	/// The limit set by [`max_encoding_message_size`](Self::max_encoding_message_size)
+	#[must_use]
+	pub fn get_max_encoding_message_size(&self) -> Option<usize> {
+		self.max_encoding_message_size
+	}
+
+	/// The encodings to accept and send compressed messages with
+	#[must_use]
+	pub fn get_compression(&self) -> &[CompressionEncoding] {
+		&self.compression
+	}
+
+	/// Whether any of the settings that apply to the whole gRPC listener are set.
+	#[cfg(feature = "http")]
+	pub(crate) fn has_listener_settings(&self) -> bool {
+		self.keepalive_interval.is_some()
+			|| self.keepalive_timeout.is_some()
+			|| self.concurrency_limit.is_some()
+			|| self.timeout.is_some()
+	}
+
+	/// A tonic server builder with the connection settings applied.
+	pub(crate) fn server(&self) -> Server {
+		let mut server = Server::builder()
+			.http2_keepalive_interval(self.keepalive_interval)
+			.http2_keepalive_timeout(self.keepalive_timeout);
+		if let Some(limit) = self.concurrency_limit {
+			server = server.concurrency_limit_per_connection(limit);
+		}
+		if let Some(timeout) = self.timeout {
+			server = server.timeout(timeout);
+		}
+		server
+	}
+}
+
+/// A gRPC server registered with [`with_configured_service`], which applies the per-service settings of
+/// [`GrpcOptions`] to it
+///
+/// Implement it for a tonic-generated server with [`configure_server!`](crate::configure_server),
+/// e.g. `fn configure(self, options: &GrpcOptions) -> Self { configure_server!(self, options) }`
+/// in `impl<T: Greeter> ConfigureServer for GreeterServer<T>`.
+///
+/// [`with_configured_service`]: crate::service::ServiceBuilder::with_configured_service
+pub trait ConfigureServer: Sized {
+	/// Apply the message size limits and compression of `options`
+	#[must_use]
+	fn configure(self, options: &GrpcOptions) -> Self;
+}
+
+/// Apply the message size limits and compression of a [`GrpcOptions`] to a tonic-generated server
+///
+/// `configure_server!(GreeterServer::new(svc), &options)` evaluates to the configured server.
+#[macro_export]
+macro_rules! configure_server {
+	($server:expr, $options:expr) => {{
+		let options: &$crate::grpc::GrpcOptions = $options;
+		let mut server = $server;
+		if let Some(limit) = options.get_max_decoding_message_size() {
+			server = server.max_decoding_message_size(limit);
+		}
+		if let Some(limit) = options.get_max_encoding_message_size() {
+			server = server.max_encoding_message_size(limit);
+		}
+		for &encoding in options.get_compression() {
+			server = server.accept_compressed(encoding).send_compressed(encoding);
+		}
+		server
+	}};
+}
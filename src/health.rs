@@ -0,0 +1,468 @@
+use std::{
+	borrow::Cow,
+	collections::BTreeMap,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use axum::{
+	Json, Router,
+	extract::{Query, State},
+	http::StatusCode,
+	response::{IntoResponse, Response},
+	routing::get,
+};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Notify, task::JoinSet};
+use tonic_health::{
+	pb::{HealthCheckRequest, health_check_response::ServingStatus, health_server::Health},
+	server::{HealthReporter, HealthService},
+};
+use tracing::{info, warn};
+
+use crate::error::Result;
+
+type CheckFn = Box<dyn FnMut() -> BoxFuture<'static, Result<()>> + Send>;
+
+/// A periodic probe of a dependency, such as a database or an upstream service
+///
+/// While any check is failing, the service and every registered gRPC service are reported as
+/// `NOT_SERVING`.
+pub struct HealthCheck {
+	name: Cow<'static, str>,
+	interval: Duration,
+	timeout: Duration,
+	failure_threshold: u32,
+	check: CheckFn,
+}
+
+impl HealthCheck {
+	/// Create a check that calls `check` every `interval`
+	///
+	/// By default the check times out after `interval` and a single failure marks it unhealthy.
+	pub fn new<F, Fut>(name: impl Into<Cow<'static, str>>, interval: Duration, mut check: F) -> Self
+	where
+		F: FnMut() -> Fut + Send + 'static,
+		Fut: Future<Output = Result<()>> + Send + 'static,
+	{
+		Self {
+			name: name.into(),
+			interval,
+			timeout: interval,
+			failure_threshold: 1,
+			check: Box::new(move || Box::pin(check())),
+		}
+	}
+
+	/// Fail the check if it does not complete within `timeout`
+	#[must_use]
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = timeout;
+		self
+	}
+
+	/// Only mark the check unhealthy after `threshold` consecutive failures
+	#[must_use]
+	pub fn failure_threshold(mut self, threshold: u32) -> Self {
+		self.failure_threshold = threshold.max(1);
+		self
+	}
+
+	#[must_use]
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+}
+
+/// The latest result of a [`HealthCheck`], as listed by the admin server
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckResult {
+	pub name: Cow<'static, str>,
+	pub healthy: bool,
+	pub consecutive_failures: u32,
+	/// The error of the last failed run, cleared when the check passes again.
+	pub error: Option<String>,
+}
+
+/// The latest result of every health check, shared between the checks and the admin server.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CheckResults(Arc<Mutex<Vec<CheckResult>>>);
+
+impl CheckResults {
+	fn register(&self, name: Cow<'static, str>) -> usize {
+		let mut results = self.0.lock().expect("health check lock poisoned");
+		results.push(CheckResult {
+			name,
+			healthy: true,
+			consecutive_failures: 0,
+			error: None,
+		});
+		results.len() - 1
+	}
+
+	/// Record a run of the check at `index`, returning whether it is healthy.
+	fn record(&self, index: usize, threshold: u32, res: Result<()>) -> bool {
+		let mut results = self.0.lock().expect("health check lock poisoned");
+		let result = &mut results[index];
+		match res {
+			Ok(()) => {
+				result.consecutive_failures = 0;
+				result.error = None;
+			}
+			Err(err) => {
+				result.consecutive_failures += 1;
+				result.error = Some(err.to_string());
+			}
+		}
+		result.healthy = result.consecutive_failures < threshold;
+		result.healthy
+	}
+
+	fn healthy(&self) -> bool {
+		let results = self.0.lock().expect("health check lock poisoned");
+		results.iter().all(|result| result.healthy)
+	}
+
+	pub(crate) fn snapshot(&self) -> Vec<CheckResult> {
+		self.0.lock().expect("health check lock poisoned").clone()
+	}
+}
+
+/// Set the overall status and that of every service in `services`.
+pub(crate) async fn set_all(
+	health: &HealthReporter,
+	services: &[&'static str],
+	status: tonic_health::ServingStatus,
+) {
+	health.set_service_status("", status).await;
+	for name in services {
+		health.set_service_status(*name, status).await;
+	}
+}
+
+/// Run every check on its interval, reporting the aggregate to `health` until the set is dropped
+///
+/// With `serving` false, e.g. because setup degraded, the service stays `NOT_SERVING`.
+pub(crate) fn supervise(
+	checks: Vec<HealthCheck>,
+	results: &CheckResults,
+	health: HealthReporter,
+	services: Vec<&'static str>,
+	serving: bool,
+) -> JoinSet<()> {
+	let mut set = JoinSet::new();
+	if checks.is_empty() {
+		return set;
+	}
+
+	let changed = Arc::new(Notify::new());
+	for check in checks {
+		let index = results.register(check.name.clone());
+		set.spawn(run_check(check, results.clone(), index, changed.clone()));
+	}
+
+	let results = results.clone();
+	set.spawn(async move {
+		let mut healthy = true;
+		loop {
+			changed.notified().await;
+			if results.healthy() == healthy {
+				continue;
+			}
+			healthy = !healthy;
+			if healthy {
+				info!("Health checks passing");
+			} else {
+				warn!("Health checks failing, reporting NOT_SERVING");
+			}
+			if serving {
+				let status = if healthy {
+					tonic_health::ServingStatus::Serving
+				} else {
+					tonic_health::ServingStatus::NotServing
+				};
+				set_all(&health, &services, status).await;
+			}
+		}
+	});
+	set
+}
+
+async fn run_check(
+	mut check: HealthCheck,
+	results: CheckResults,
+	index: usize,
+	changed: Arc<Notify>,
+) {
+	let name = check.name.as_ref();
+	let mut interval = tokio::time::interval(check.interval);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+	let mut healthy = true;
+	loop {
+		interval.tick().await;
+		let res = match tokio::time::timeout(check.timeout, (check.check)()).await {
+			Ok(res) => res,
+			Err(_) => Err(crate::error::Error::Other(
+				format!("timed out after {:?}", check.timeout).into(),
+			)),
+		};
+		if let Err(err) = &res {
+			warn!(check = name, "Health check failed: {err}");
+		}
+		let now = results.record(index, check.failure_threshold, res);
+		if now != healthy {
+			healthy = now;
+			changed.notify_one();
+		}
+	}
+}
+
+#[derive(Clone)]
+struct Probes {
+	health: Arc<HealthService>,
+	services: Arc<[&'static str]>,
+}
+
+#[derive(Deserialize)]
+struct ReadyQuery {
+	service: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+	status: &'static str,
+	services: BTreeMap<&'static str, &'static str>,
+}
+
+/// `/healthz` and `/readyz`, mirroring the gRPC health service
+///
+/// `/healthz` returns 200 while the process is up. `/readyz` returns 200 if the service and every
+/// gRPC service in `services` is `SERVING`, and 503 otherwise, with each status in the body;
+/// `/readyz?service=<name>` checks one gRPC service and returns 404 if it is unknown.
+pub(crate) fn router(health: HealthReporter, services: Arc<[&'static str]>) -> Router {
+	Router::new()
+		.route("/healthz", get(|| async { "ok" }))
+		.route("/readyz", get(readyz))
+		.with_state(Probes {
+			health: Arc::new(HealthService::from_health_reporter(health)),
+			services,
+		})
+}
+
+async fn readyz(State(probes): State<Probes>, Query(query): Query<ReadyQuery>) -> Response {
+	if let Some(service) = query.service {
+		return match status(&probes.health, service).await {
+			Some(status) => (status_code(status), status.as_str_name()).into_response(),
+			None => (StatusCode::NOT_FOUND, "service not registered").into_response(),
+		};
+	}
+
+	let overall = status(&probes.health, String::new())
+		.await
+		.unwrap_or(ServingStatus::Unknown);
+	let mut ready = overall == ServingStatus::Serving;
+	let mut services = BTreeMap::new();
+	for name in probes.services.iter() {
+		let status = status(&probes.health, (*name).to_string())
+			.await
+			.unwrap_or(ServingStatus::ServiceUnknown);
+		ready &= status == ServingStatus::Serving;
+		services.insert(*name, status.as_str_name());
+	}
+
+	let code = if ready {
+		StatusCode::OK
+	} else {
+		StatusCode::SERVICE_UNAVAILABLE
+	};
+	(
+		code,
+		Json(Readiness {
+			status: overall.as_str_name(),
+			services,
+		}),
+	)
+		.into_response()
+}
+
+async fn status(health: &HealthService, service: String) -> Option<ServingStatus> {
+	let res = health
+		.check(tonic::Request::new(HealthCheckRequest { service }))
+		.await
+		.ok()?;
+	Some(res.get_ref().status())
+}
+
+fn status_code(status: ServingStatus) -> StatusCode {
+	if status == ServingStatus::Serving {
+		StatusCode::OK
+	} else {
+		StatusCode::SERVICE_UNAVAILABLE
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicBool, Ordering};
+
+	use tokio::time::sleep;
+
+	use super::*;
+	use crate::error::Error;
+
+	const SERVICES: [&str; 1] = ["greeter.Greeter"];
+
+	/// A check run every second, failing while `failing` is set.
+	fn check(name: &'static str, failing: &Arc<AtomicBool>) -> HealthCheck {
+		let failing = failing.clone();
+		HealthCheck::new(name, Duration::from_secs(1), move || {
+			let failing = failing.load(Ordering::SeqCst);
+			async move {
+				if failing {
+					Err(Error::Other("down".into()))
+				} else {
+					Ok(())
+				}
+			}
+		})
+	}
+
+	/// A health service reporting `status` for the service and [`SERVICES`].
+	async fn health(status: tonic_health::ServingStatus) -> (HealthReporter, HealthService) {
+		let (reporter, _) = tonic_health::server::health_reporter();
+		set_all(&reporter, &SERVICES, status).await;
+		let service = HealthService::from_health_reporter(reporter.clone());
+		(reporter, service)
+	}
+
+	/// The status of the service and of [`SERVICES`], which must agree.
+	async fn reported(health: &HealthService) -> ServingStatus {
+		let overall = status(health, String::new()).await.unwrap();
+		for name in SERVICES {
+			assert_eq!(status(health, name.to_string()).await, Some(overall));
+		}
+		overall
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn reports_not_serving_while_a_check_fails() {
+		let failing = Arc::new(AtomicBool::new(false));
+		let results = CheckResults::default();
+		let (reporter, health) = health(tonic_health::ServingStatus::Serving).await;
+		let _checks = supervise(
+			vec![check("db", &failing)],
+			&results,
+			reporter,
+			SERVICES.to_vec(),
+			true,
+		);
+
+		sleep(Duration::from_millis(1500)).await;
+		assert_eq!(reported(&health).await, ServingStatus::Serving);
+
+		failing.store(true, Ordering::SeqCst);
+		sleep(Duration::from_secs(1)).await;
+		assert_eq!(reported(&health).await, ServingStatus::NotServing);
+		let [result] = &results.snapshot()[..] else {
+			panic!("expected one check");
+		};
+		assert!(!result.healthy);
+		assert_eq!(result.error.as_deref(), Some("down"));
+
+		failing.store(false, Ordering::SeqCst);
+		sleep(Duration::from_secs(1)).await;
+		assert_eq!(reported(&health).await, ServingStatus::Serving);
+		assert_eq!(results.snapshot()[0].consecutive_failures, 0);
+		assert_eq!(results.snapshot()[0].error, None);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn waits_for_the_failure_threshold() {
+		let failing = Arc::new(AtomicBool::new(true));
+		let results = CheckResults::default();
+		let (reporter, health) = health(tonic_health::ServingStatus::Serving).await;
+		let _checks = supervise(
+			vec![check("db", &failing).failure_threshold(3)],
+			&results,
+			reporter,
+			SERVICES.to_vec(),
+			true,
+		);
+
+		sleep(Duration::from_millis(1500)).await;
+		assert_eq!(reported(&health).await, ServingStatus::Serving);
+		assert_eq!(results.snapshot()[0].consecutive_failures, 2);
+		assert!(results.snapshot()[0].healthy);
+
+		sleep(Duration::from_secs(1)).await;
+		assert_eq!(reported(&health).await, ServingStatus::NotServing);
+		assert_eq!(results.snapshot()[0].consecutive_failures, 3);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn stays_not_serving_until_every_check_passes() {
+		let db = Arc::new(AtomicBool::new(true));
+		let cache = Arc::new(AtomicBool::new(true));
+		let results = CheckResults::default();
+		let (reporter, health) = health(tonic_health::ServingStatus::Serving).await;
+		let _checks = supervise(
+			vec![check("db", &db), check("cache", &cache)],
+			&results,
+			reporter,
+			SERVICES.to_vec(),
+			true,
+		);
+
+		sleep(Duration::from_millis(500)).await;
+		assert_eq!(reported(&health).await, ServingStatus::NotServing);
+
+		db.store(false, Ordering::SeqCst);
+		sleep(Duration::from_secs(1)).await;
+		assert_eq!(reported(&health).await, ServingStatus::NotServing);
+
+		cache.store(false, Ordering::SeqCst);
+		sleep(Duration::from_secs(1)).await;
+		assert_eq!(reported(&health).await, ServingStatus::Serving);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn fails_checks_that_time_out() {
+		let results = CheckResults::default();
+		let (reporter, health) = health(tonic_health::ServingStatus::Serving).await;
+		let slow = HealthCheck::new("slow", Duration::from_secs(1), || async {
+			sleep(Duration::from_mins(1)).await;
+			Ok(())
+		})
+		.timeout(Duration::from_millis(200));
+		let _checks = supervise(vec![slow], &results, reporter, SERVICES.to_vec(), true);
+
+		sleep(Duration::from_millis(500)).await;
+		assert_eq!(reported(&health).await, ServingStatus::NotServing);
+		assert_eq!(
+			results.snapshot()[0].error.as_deref(),
+			Some("timed out after 200ms")
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn stays_not_serving_after_a_degraded_setup() {
+		let failing = Arc::new(AtomicBool::new(false));
+		let results = CheckResults::default();
+		let (reporter, health) = health(tonic_health::ServingStatus::NotServing).await;
+		let _checks = supervise(
+			vec![check("db", &failing)],
+			&results,
+			reporter,
+			SERVICES.to_vec(),
+			false,
+		);
+
+		for fail in [false, true, false] {
+			failing.store(fail, Ordering::SeqCst);
+			sleep(Duration::from_secs(1)).await;
+			assert_eq!(reported(&health).await, ServingStatus::NotServing);
+			assert_eq!(results.snapshot()[0].healthy, !fail);
+		}
+	}
+}
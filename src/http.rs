@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use axum::{
+	extract::DefaultBodyLimit,
+	http::{HeaderName, HeaderValue},
+};
+use tower_http::{
+	cors::{AllowOrigin, Any, CorsLayer},
+	limit::RequestBodyLimitLayer,
+	sensitive_headers::SetSensitiveHeadersLayer,
+	timeout::TimeoutLayer,
+	trace::{DefaultMakeSpan, TraceLayer},
+};
+
+use crate::{
+	config::Config,
+	error::{Error, Result},
+};
+
+/// Wrap `router` in the HTTP middleware enabled by the `http_*` fields of `config`
+///
+/// From the outside in: sensitive header redaction, tracing, CORS, the request timeout, the body
+/// limit and compression.
+///
+/// # Errors
+///
+/// Returns [`Error::Config`] if a CORS origin or sensitive header name is invalid.
+pub(crate) fn layers(router: axum::Router, config: &Config) -> Result<axum::Router> {
+	#[cfg(feature = "compression")]
+	let router = if config.http_compression {
+		router
+			.layer(tower_http::compression::CompressionLayer::new())
+			.layer(tower_http::decompression::RequestDecompressionLayer::new())
+	} else {
+		router
+	};
+
+	let router = match config.http_body_limit {
+		Some(limit) => router
+			.layer(DefaultBodyLimit::disable())
+			.layer(RequestBodyLimitLayer::new(limit)),
+		None => router,
+	};
+
+	let router = match config.http_timeout {
+		Some(timeout) => router.layer(TimeoutLayer::new(Duration::from_secs(timeout))),
+		None => router,
+	};
+
+	let router = match cors(&config.http_cors_origins)? {
+		Some(cors) => router.layer(cors),
+		None => router,
+	};
+
+	let router = if config.http_trace_headers {
+		router.layer(
+			TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().include_headers(true)),
+		)
+	} else {
+		router.layer(TraceLayer::new_for_http())
+	};
+
+	let sensitive = config
+		.http_sensitive_headers
+		.iter()
+		.map(|name| {
+			HeaderName::try_from(name.as_str())
+				.map_err(|_| Error::Config(format!("invalid sensitive header `{name}`")))
+		})
+		.collect::<Result<Vec<_>>>()?;
+	Ok(if sensitive.is_empty() {
+		router
+	} else {
+		router.layer(SetSensitiveHeadersLayer::new(sensitive))
+	})
+}
+
+/// A CORS policy allowing `origins`, or any origin if they include `*`; `None` if empty.
+fn cors(origins: &[String]) -> Result<Option<CorsLayer>> {
+	if origins.is_empty() {
+		return Ok(None);
+	}
+	let allow_origin = if origins.iter().any(|o| o == "*") {
+		AllowOrigin::any()
+	} else {
+		let origins = origins
+			.iter()
+			.map(|origin| {
+				HeaderValue::try_from(origin.as_str())
+					.map_err(|_| Error::Config(format!("invalid CORS origin `{origin}`")))
+			})
+			.collect::<Result<Vec<_>>>()?;
+		AllowOrigin::list(origins)
+	};
+	Ok(Some(
+		CorsLayer::new()
+			.allow_origin(allow_origin)
+			.allow_methods(Any)
+			.allow_headers(Any),
+	))
+}
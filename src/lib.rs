@@ -3,34 +3,71 @@
 use std::convert::Infallible;
 
 use axum::http::Request;
-use tonic::{body::Body, server::NamedService, service::Routes};
+use tonic::{body::Body, server::NamedService};
 use uuid::{Uuid, uuid};
 
+mod admin;
 #[cfg(feature = "cache")]
 pub mod cache;
 pub mod config;
 pub mod error;
+pub mod grpc;
+pub mod health;
+#[cfg(feature = "http")]
+mod http;
+pub mod lifecycle;
+mod listen;
+pub mod module;
 pub mod service;
+pub mod setup;
+#[cfg(feature = "systemd")]
+mod systemd;
+pub mod task;
 #[cfg(feature = "telemetry")]
 pub mod telemetry;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod util;
 
 #[cfg(feature = "derive")]
 pub use runesys_derive::Service;
 
+#[cfg(all(feature = "systemd", not(unix)))]
+compile_error!("the `systemd` feature is only supported on Unix");
+
 use crate::service::ServiceBuilder;
 
+/// Serialises tests that change environment variables.
+#[cfg(test)]
+pub(crate) static ENV_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
 const NAMESPACE: Uuid = uuid!("466b8727-8f7f-4596-b59d-92b2252b2c4b");
 
 pub trait Service {
 	const INFO: ServiceInfo;
-	#[cfg(debug_assertions)]
-	const FILE_DESCRIPTOR_SET: &'static [u8];
+	/// Encoded file descriptor set served by gRPC reflection; empty if the service has none.
+	const FILE_DESCRIPTOR_SET: &'static [u8] = &[];
 
 	type Server;
 	fn new_server(self) -> Self::Server;
 
-	fn builder(self) -> ServiceBuilder<Self>
+	/// Apply the per-service [`GrpcOptions`](grpc::GrpcOptions) to a server from [`new_server`]
+	///
+	/// The derive sets the message size limits and compression; by default the server is
+	/// returned unchanged.
+	///
+	/// [`new_server`]: Service::new_server
+	fn configure_server(server: Self::Server, options: &grpc::GrpcOptions) -> Self::Server {
+		let _ = options;
+		server
+	}
+
+	/// Start a [`ServiceBuilder`] serving this service
+	///
+	/// # Errors
+	///
+	/// Returns [`Error::Config`](error::Error::Config) if the configuration is invalid.
+	fn builder(self) -> error::Result<ServiceBuilder>
 	where
 		Self::Server: tonic::codegen::Service<Request<Body>, Error = Infallible>
 			+ NamedService
@@ -47,47 +84,107 @@ pub trait Service {
 	}
 }
 
+#[derive(Debug, Clone, Copy, serde::Serialize)]
 pub struct ServiceInfo {
 	pub name: &'static str,
 	pub pkg: &'static str,
 	pub version: &'static str,
 }
 
+/// Build a [`ServiceInfo`] for the calling crate, e.g. for [`ServiceBuilder::worker`]
+#[macro_export]
+macro_rules! service_info {
+	($name:expr) => {
+		$crate::ServiceInfo {
+			name: $name,
+			pkg: env!("CARGO_PKG_NAME"),
+			version: env!("CARGO_PKG_VERSION"),
+		}
+	};
+}
+
 impl ServiceInfo {
+	#[must_use]
 	pub fn uuid(&self) -> Uuid {
 		Uuid::new_v5(&NAMESPACE, self.pkg.as_bytes())
 	}
 }
 
 pub mod tracing {
+	use std::sync::OnceLock;
+
 	#[cfg(feature = "telemetry")]
 	use opentelemetry::trace::TracerProvider;
 	use tracing::level_filters::LevelFilter;
-	use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+	use tracing_subscriber::{
+		EnvFilter, Registry, filter::ParseError, layer::SubscriberExt, reload,
+		util::SubscriberInitExt,
+	};
 
 	use crate::ServiceInfo;
 
+	static LOG_FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+	/// The log filter currently applied by the subscriber from [`init`], or `None` if another
+	/// subscriber was already set
+	#[must_use]
+	pub fn log_filter() -> Option<String> {
+		LOG_FILTER
+			.get()
+			.and_then(|handle| handle.with_current(ToString::to_string).ok())
+	}
+
+	/// Parse log filter `directives` as they would be read from `RUST_LOG`.
+	pub(crate) fn parse_filter(directives: &str) -> Result<EnvFilter, ParseError> {
+		EnvFilter::builder()
+			.with_default_directive(LevelFilter::INFO.into())
+			.parse(directives)
+	}
+
+	/// Filter logs with `directives`, or with `RUST_LOG` again if `None`, unless another
+	/// subscriber was already set.
+	pub(crate) fn set_log_filter(directives: Option<&str>) {
+		let Some(handle) = LOG_FILTER.get() else {
+			return;
+		};
+		let filter = match directives.map(parse_filter) {
+			Some(Ok(filter)) => filter,
+			Some(Err(err)) => {
+				tracing::error!("Invalid log filter, keeping the current one: {err}");
+				return;
+			}
+			None => from_env(),
+		};
+		if let Err(err) = handle.reload(filter) {
+			tracing::error!("Cannot replace the log filter: {err}");
+		}
+	}
+
+	fn from_env() -> EnvFilter {
+		EnvFilter::builder()
+			.with_default_directive(LevelFilter::INFO.into())
+			.from_env_lossy()
+	}
+
 	#[allow(private_interfaces)]
 	pub fn init(info: &ServiceInfo) {
 		if tracing::dispatcher::has_been_set() {
 			return;
 		}
 
+		let (filter, handle) = reload::Layer::new(from_env());
+		let _ = LOG_FILTER.set(handle);
 		let subscriber = tracing_subscriber::registry()
-			.with(
-				tracing_subscriber::EnvFilter::builder()
-					.with_default_directive(LevelFilter::INFO.into())
-					.from_env_lossy(),
-			)
+			.with(filter)
 			.with(tracing_subscriber::fmt::layer());
 
 		#[cfg(feature = "telemetry")]
 		let subscriber = subscriber
 			.with(tracing_opentelemetry::OpenTelemetryLayer::new(
-				crate::telemetry::init_tracer_provider(&info).tracer(info.pkg),
+				crate::telemetry::init_tracer_provider(info).tracer(info.pkg),
 			))
 			.with(tracing_opentelemetry::MetricsLayer::new(
-				crate::telemetry::init_meter_provider(&info),
+				crate::telemetry::init_meter_provider(info),
 			));
 
 		subscriber.init();
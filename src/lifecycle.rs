@@ -0,0 +1,123 @@
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use futures::future::LocalBoxFuture;
+use tracing::{error, warn};
+
+use crate::{error::Result, setup::SetupError};
+
+pub(crate) type Hook<T> = Box<dyn FnOnce(T) -> LocalBoxFuture<'static, Result<()>>>;
+pub(crate) type SetupFailedHook = Box<dyn FnOnce(&SetupError)>;
+
+/// An address a server accepts connections on
+#[derive(Debug, Clone)]
+pub enum ListenAddr {
+	Tcp(SocketAddr),
+	/// A Unix domain socket; the path is `None` for unnamed and abstract sockets.
+	#[cfg(unix)]
+	Unix(Option<std::path::PathBuf>),
+}
+
+impl fmt::Display for ListenAddr {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Tcp(addr) => addr.fmt(f),
+			#[cfg(unix)]
+			Self::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+			#[cfg(unix)]
+			Self::Unix(None) => f.write_str("unix:<unnamed>"),
+		}
+	}
+}
+
+/// The addresses the servers are bound to, passed to [`on_ready`] hooks
+///
+/// When gRPC and HTTP share a port, both are set to the same address.
+///
+/// [`on_ready`]: crate::service::ServiceBuilder::on_ready
+#[derive(Debug, Clone)]
+pub struct Ready {
+	pub grpc: Option<ListenAddr>,
+	#[cfg(feature = "http")]
+	pub http: Option<ListenAddr>,
+	pub admin: Option<ListenAddr>,
+}
+
+/// Callbacks around the phases of [`ServiceBuilder::run`](crate::service::ServiceBuilder::run)
+#[derive(Default)]
+pub(crate) struct Hooks {
+	pub(crate) setup_complete: Vec<Hook<()>>,
+	pub(crate) setup_failed: Vec<SetupFailedHook>,
+	pub(crate) ready: Vec<Hook<Ready>>,
+	pub(crate) shutdown: Vec<Hook<()>>,
+	pub(crate) stopped: Vec<Hook<()>>,
+}
+
+/// Run `hooks` in order, stopping at the first that fails.
+pub(crate) async fn run<T: Clone>(hooks: Vec<Hook<T>>, arg: T) -> Result<()> {
+	for hook in hooks {
+		hook(arg.clone()).await?;
+	}
+	Ok(())
+}
+
+/// Run every hook in `hooks`, logging those that fail, and give up on the rest once `timeout` has
+/// passed.
+pub(crate) async fn run_all(hooks: Vec<Hook<()>>, what: &str, timeout: Duration) {
+	let run = async {
+		for hook in hooks {
+			if let Err(err) = hook(()).await {
+				error!("{what} hook failed: {err}");
+			}
+		}
+	};
+	if tokio::time::timeout(timeout, run).await.is_err() {
+		warn!("{what} hooks did not finish within {timeout:?}, skipping the rest");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, rc::Rc};
+
+	use super::*;
+	use crate::error::Error;
+
+	/// A hook that waits `secs` seconds, then records `name` and returns `result`.
+	fn hook(log: &Rc<RefCell<Vec<&'static str>>>, name: &'static str, secs: u64) -> Hook<()> {
+		let log = log.clone();
+		Box::new(move |()| {
+			Box::pin(async move {
+				tokio::time::sleep(Duration::from_secs(secs)).await;
+				log.borrow_mut().push(name);
+				if name == "failing" {
+					return Err(Error::Config("hook failed".to_string()));
+				}
+				Ok(())
+			})
+		})
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn runs_every_hook_in_order_despite_failures() {
+		let log = Rc::default();
+		let hooks = vec![hook(&log, "failing", 1), hook(&log, "second", 1)];
+
+		run_all(hooks, "Shutdown", Duration::from_secs(10)).await;
+		assert_eq!(*log.borrow(), ["failing", "second"]);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn gives_up_on_hooks_after_the_timeout() {
+		let log = Rc::default();
+		let hooks = vec![
+			hook(&log, "first", 1),
+			hook(&log, "hung", 3600),
+			hook(&log, "skipped", 1),
+		];
+		let started = tokio::time::Instant::now();
+
+		run_all(hooks, "Shutdown", Duration::from_secs(10)).await;
+		assert_eq!(started.elapsed(), Duration::from_secs(10));
+		assert_eq!(*log.borrow(), ["first"]);
+	}
+}
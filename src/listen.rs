@@ -0,0 +1,197 @@
+use std::{io, net::SocketAddr};
+#[cfg(unix)]
+use std::{
+	os::unix::fs::{FileTypeExt, PermissionsExt},
+	path::{Path, PathBuf},
+};
+
+use futures::Stream;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::lifecycle::ListenAddr;
+
+/// Where a server accepts connections
+pub(crate) enum Listen {
+	/// Bind a TCP listener on this address.
+	Tcp(SocketAddr),
+	/// Use a TCP listener that is already bound, by the caller or by systemd.
+	Listener(TcpListener),
+	/// Bind a Unix domain socket at this path.
+	#[cfg(unix)]
+	Unix(PathBuf),
+	/// Use a Unix domain socket that is already bound by systemd.
+	#[cfg(feature = "systemd")]
+	UnixListener(UnixListener),
+}
+
+impl Listen {
+	/// Prefer an already bound listener, then a configured socket path, then `address:port`.
+	pub(crate) fn resolve(
+		listener: Option<Listen>,
+		#[cfg(unix)] socket: Option<&PathBuf>,
+		port: u16,
+	) -> Self {
+		if let Some(listener) = listener {
+			return listener;
+		}
+		#[cfg(unix)]
+		if let Some(path) = socket {
+			return Self::Unix(path.clone());
+		}
+		Self::Tcp(SocketAddr::new(crate::config::config().address, port))
+	}
+
+	/// Bind the listener, applying `socket_mode` to a new Unix domain socket.
+	pub(crate) async fn bind(self) -> io::Result<Bound> {
+		match self {
+			Self::Tcp(addr) => Ok(Bound::Tcp(TcpListener::bind(addr).await?)),
+			Self::Listener(listener) => Ok(Bound::Tcp(listener)),
+			#[cfg(unix)]
+			Self::Unix(path) => Ok(Bound::Unix(UnixSocket::bind(
+				&path,
+				crate::config::config().socket_mode,
+			)?)),
+			#[cfg(feature = "systemd")]
+			Self::UnixListener(listener) => Ok(Bound::Unix(UnixSocket {
+				listener,
+				path: None,
+			})),
+		}
+	}
+}
+
+/// A listener that is ready to accept connections
+pub(crate) enum Bound {
+	Tcp(TcpListener),
+	#[cfg(unix)]
+	Unix(UnixSocket),
+}
+
+impl Bound {
+	pub(crate) fn local_addr(&self) -> io::Result<ListenAddr> {
+		match self {
+			Self::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+			#[cfg(unix)]
+			Self::Unix(socket) => socket.local_addr(),
+		}
+	}
+}
+
+/// A bound Unix domain socket, whose file is removed when it is dropped if runesys created it
+#[cfg(unix)]
+pub(crate) struct UnixSocket {
+	listener: UnixListener,
+	path: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+	/// Bind `path`, replacing a stale socket file, and apply `mode` to it if set.
+	///
+	/// Fails rather than replace anything at `path` that is not a socket.
+	fn bind(path: &Path, mode: Option<u32>) -> io::Result<Self> {
+		match std::fs::symlink_metadata(path) {
+			Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+			Ok(_) => {
+				return Err(io::Error::new(
+					io::ErrorKind::AlreadyExists,
+					format!("{} exists and is not a socket", path.display()),
+				));
+			}
+			Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+			Err(err) => return Err(err),
+		}
+
+		let listener = UnixListener::bind(path)?;
+		if let Some(mode) = mode {
+			std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+		}
+		Ok(Self {
+			listener,
+			path: Some(path.to_path_buf()),
+		})
+	}
+
+	fn local_addr(&self) -> io::Result<ListenAddr> {
+		let addr = self.listener.local_addr()?;
+		Ok(ListenAddr::Unix(addr.as_pathname().map(Path::to_path_buf)))
+	}
+
+	/// The socket path, for logging
+	pub(crate) fn display(&self) -> String {
+		match self.local_addr() {
+			Ok(addr) => addr.to_string(),
+			Err(_) => "unix:<unknown>".to_string(),
+		}
+	}
+
+	/// The accepted connections as a stream, for tonic's `serve_with_incoming`
+	pub(crate) fn into_stream(self) -> impl Stream<Item = io::Result<UnixStream>> {
+		futures::stream::unfold(self, |socket| async move {
+			let conn = socket.listener.accept().await.map(|(stream, _)| stream);
+			Some((conn, socket))
+		})
+	}
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+	fn drop(&mut self) {
+		if let Some(path) = &self.path {
+			let _ = std::fs::remove_file(path);
+		}
+	}
+}
+
+#[cfg(all(unix, feature = "http"))]
+impl axum::serve::Listener for UnixSocket {
+	type Io = UnixStream;
+	type Addr = tokio::net::unix::SocketAddr;
+
+	fn accept(&mut self) -> impl Future<Output = (Self::Io, Self::Addr)> + Send {
+		axum::serve::Listener::accept(&mut self.listener)
+	}
+
+	fn local_addr(&self) -> io::Result<Self::Addr> {
+		self.listener.local_addr()
+	}
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+	use super::*;
+
+	/// A path in the temporary directory, unique to this process and test.
+	fn temp_path(name: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("runesys-{}-{name}", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		path
+	}
+
+	#[tokio::test]
+	async fn replaces_a_stale_socket() {
+		let path = temp_path("stale.sock");
+		drop(UnixListener::bind(&path).unwrap());
+
+		let socket = UnixSocket::bind(&path, Some(0o600)).unwrap();
+		let metadata = std::fs::metadata(&path).unwrap();
+		assert!(metadata.file_type().is_socket());
+		assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+
+		drop(socket);
+		assert!(!path.exists());
+	}
+
+	#[tokio::test]
+	async fn keeps_a_file_that_is_not_a_socket() {
+		let path = temp_path("precious.txt");
+		std::fs::write(&path, "precious").unwrap();
+
+		let err = UnixSocket::bind(&path, None).err().unwrap();
+		assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+		assert_eq!(std::fs::read_to_string(&path).unwrap(), "precious");
+		std::fs::remove_file(&path).unwrap();
+	}
+}
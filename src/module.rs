@@ -0,0 +1,29 @@
+use serde::de::DeserializeOwned;
+
+use crate::{config::Validate, error::Result, service::ServiceBuilder};
+
+/// A reusable component added to a service with [`ServiceBuilder::with_module`]
+///
+/// A module registers its parts through the builder, like any service would: gRPC services,
+/// HTTP routes, setup steps, background tasks and extensions. Its settings are read from the
+/// table named after it, e.g. `[auth]` in the config file and `<prefix>AUTH__ISSUER` in the
+/// environment.
+pub trait Module {
+	/// Settings of the module, extracted with [`config::extract_section`] from the table named
+	/// [`name`](Module::name)
+	///
+	/// Use [`IgnoredAny`](serde::de::IgnoredAny) for a module without settings.
+	///
+	/// [`config::extract_section`]: crate::config::extract_section
+	type Config: DeserializeOwned + Validate;
+
+	/// Name of the module, for logs and its config table
+	fn name(&self) -> &'static str;
+
+	/// Add the module's parts to `builder`
+	///
+	/// # Errors
+	///
+	/// Returns an error if the module cannot be set up.
+	fn register(self, builder: ServiceBuilder, config: Self::Config) -> Result<ServiceBuilder>;
+}
@@ -1,113 +1,256 @@
-use std::{convert::Infallible, net::SocketAddr, pin::Pin};
+use std::{borrow::Cow, convert::Infallible, net::SocketAddr, time::Duration};
 
-use futures::future::select_all;
+use axum::{http::Extensions, response::IntoResponse, routing::Route};
 #[cfg(feature = "db")]
 use sqlx::{PgPool, migrate::MigrateError};
-use tokio::{net::TcpListener, task::JoinHandle};
+use tokio::{
+	net::TcpListener,
+	task::{JoinError, JoinSet},
+};
+pub use tokio_util::sync::CancellationToken;
 use tonic::{
 	body::Body,
 	codegen::{Service, http::Request},
 	server::NamedService,
 	service::Routes,
-	transport::Server,
+	transport::server::TcpIncoming,
+};
+use tonic_health::{
+	ServingStatus,
+	pb::health_server::{Health, HealthServer},
+	server::{HealthReporter, health_reporter},
 };
-use tonic_health::server::health_reporter;
-use tower::util::option_layer;
-use tower_http::{add_extension::AddExtensionLayer, trace::TraceLayer};
-use tracing::info;
+use tower::Layer;
+#[cfg(feature = "grpc-web")]
+pub use tower_http::cors::CorsLayer;
+use tower_http::trace::TraceLayer;
+use tracing::{error, info, warn};
+#[cfg(feature = "db")]
 use url::Url;
 
-use crate::error::{Error, Result};
+#[cfg(feature = "tls")]
+use crate::tls::TlsListener;
+use crate::{
+	ServiceInfo,
+	error::{Error, Result},
+	grpc::{ConfigureServer, GrpcOptions},
+	health::{CheckResults, HealthCheck},
+	lifecycle::{self, Hooks, ListenAddr, Ready},
+	listen::{Bound, Listen},
+	module::Module,
+	setup::{ApplyFn, SetupError, SetupStep, SetupTask},
+	task::{Task, TaskStatuses},
+};
 
-type BoxFut<T> = Pin<Box<dyn Future<Output = T> + 'static>>;
-type ApplyFn<T> = Box<dyn FnOnce(T) -> T>;
-type SetupTask<T> = BoxFut<Result<Option<ApplyFn<T>>>>;
+/// Registers a gRPC service once the [`GrpcOptions`] are final.
+type AddService = Box<dyn FnOnce(Routes, &GrpcOptions) -> Routes>;
+/// Wraps a router in a caller-provided layer.
+type LayerFn = Box<dyn FnOnce(axum::Router) -> axum::Router>;
 
 /// A generic microservice builder for gRPC + optional HTTP
-pub struct ServiceBuilder<R>
-where
-	R: crate::Service,
-{
-	grpc: Routes,
+pub struct ServiceBuilder {
+	info: ServiceInfo,
+	/// gRPC services, added when the service is run; without one no gRPC server is started.
+	grpc_services: Vec<AddService>,
+	grpc_options: GrpcOptions,
+	/// Caller-provided gRPC middleware, outermost first.
+	grpc_layers: Vec<LayerFn>,
+	/// gRPC services whose health is reported by the health service.
+	health_services: Vec<&'static str>,
+	/// Encoded file descriptor sets and service names served by the reflection service.
+	#[cfg(feature = "reflection")]
+	reflection: Vec<(&'static [u8], &'static str)>,
+	/// CORS policy for gRPC-Web; `None` unless gRPC-Web is enabled.
+	#[cfg(feature = "grpc-web")]
+	grpc_web: Option<CorsLayer>,
 	#[cfg(feature = "http")]
 	http: Option<axum::Router>,
-	#[cfg(feature = "db")]
-	pg_pool: Option<PgPool>,
+	/// Caller-provided HTTP middleware, outermost first.
+	#[cfg(feature = "http")]
+	http_layers: Vec<LayerFn>,
+	/// Listeners bound by the caller, used instead of the configured addresses.
+	grpc_listener: Option<TcpListener>,
+	#[cfg(feature = "http")]
+	http_listener: Option<TcpListener>,
+	/// Values inserted into the extensions of every gRPC and HTTP request.
+	extensions: Extensions,
 
-	setup_tasks: Vec<SetupTask<Self>>,
-	handles: Vec<JoinHandle<Result<()>>>,
+	setup_steps: Vec<SetupStep<Self>>,
+	tasks: Vec<Task>,
+	health_checks: Vec<HealthCheck>,
+	hooks: Hooks,
 }
 
 pub struct ServiceState {
 	pub health_reporter: tonic_health::server::HealthReporter,
 }
 
-#[cfg(debug_assertions)]
+/// Register the gRPC reflection services for `S`, if it has a file descriptor set.
+///
+/// Both the v1 and v1alpha protocols are served, unless [`grpc_reflection`] is off.
+///
+/// [`grpc_reflection`]: crate::config::Config::grpc_reflection
+///
+/// # Errors
+///
+/// Returns [`Error::Reflection`] if the file descriptor set cannot be decoded.
+#[cfg(feature = "reflection")]
 pub fn add_reflection_service<S>(r: Routes) -> Result<Routes>
 where
 	S: crate::Service,
 	S::Server: NamedService,
 {
-	if S::FILE_DESCRIPTOR_SET.is_empty() {
+	if S::FILE_DESCRIPTOR_SET.is_empty() || !reflection_enabled() {
 		return Ok(r);
 	}
-	let reflection = tonic_reflection::server::Builder::configure()
-		.register_encoded_file_descriptor_set(S::FILE_DESCRIPTOR_SET)
-		.with_service_name(S::Server::NAME)
-		.build_v1()?;
+	let (v1, v1alpha) = reflection_service(&[(S::FILE_DESCRIPTOR_SET, S::Server::NAME)])?;
+	Ok(r.add_service(v1).add_service(v1alpha))
+}
+
+/// A CORS policy allowing the headers gRPC-Web clients send and read, for [`ServiceBuilder::with_grpc_web`]
+///
+/// No origins are allowed; add them with [`CorsLayer::allow_origin`].
+#[cfg(feature = "grpc-web")]
+pub fn grpc_web_cors() -> CorsLayer {
+	use axum::http::{HeaderName, Method};
 
-	Ok(r.add_service(reflection))
+	CorsLayer::new()
+		.allow_methods([Method::POST])
+		.allow_headers([
+			HeaderName::from_static("content-type"),
+			HeaderName::from_static("authorization"),
+			HeaderName::from_static("x-grpc-web"),
+			HeaderName::from_static("x-user-agent"),
+			HeaderName::from_static("grpc-timeout"),
+		])
+		.expose_headers([
+			HeaderName::from_static("grpc-status"),
+			HeaderName::from_static("grpc-message"),
+			HeaderName::from_static("grpc-status-details-bin"),
+		])
+		.max_age(Duration::from_hours(24))
 }
 
-#[cfg(not(debug_assertions))]
+/// Reflection is only available with the `reflection` feature; this is a no-op.
+///
+/// # Errors
+///
+/// Never returns an error.
+#[cfg(not(feature = "reflection"))]
 pub fn add_reflection_service<S>(r: Routes) -> Result<Routes> {
 	Ok(r)
 }
 
-impl<R> Default for ServiceBuilder<R>
-where
-	R: crate::Service,
-{
-	fn default() -> Self {
-		Self {
-			grpc: Routes::default(),
+impl ServiceBuilder {
+	fn init(info: ServiceInfo) -> Result<Self> {
+		crate::config::default_env_naming(&crate::config::EnvNaming::from_pkg(info.pkg))?;
+		crate::tracing::init(&info);
+		let config = crate::config::load()?;
+		if config.log_filter.is_some() {
+			crate::tracing::set_log_filter(config.log_filter.as_deref());
+		}
+
+		Ok(Self {
+			info,
+			grpc_services: Vec::new(),
+			grpc_options: GrpcOptions::from_config(config),
+			grpc_layers: Vec::new(),
+			health_services: Vec::new(),
+			#[cfg(feature = "reflection")]
+			reflection: Vec::new(),
+			#[cfg(feature = "grpc-web")]
+			grpc_web: None,
 			#[cfg(feature = "http")]
 			http: None,
-			#[cfg(feature = "db")]
-			pg_pool: None,
-			setup_tasks: Vec::new(),
-			handles: Vec::new(),
-		}
+			#[cfg(feature = "http")]
+			http_layers: Vec::new(),
+			grpc_listener: None,
+			#[cfg(feature = "http")]
+			http_listener: None,
+			extensions: Extensions::new(),
+			setup_steps: Vec::new(),
+			tasks: Vec::new(),
+			health_checks: Vec::new(),
+			hooks: Hooks::default(),
+		})
 	}
-}
 
-impl<R> ServiceBuilder<R>
-where
-	R: crate::Service + 'static,
-	R::Server: NamedService,
-{
 	/// Initialize tracing, load config, setup health + gRPC address
-	pub fn new(svc: R) -> Self
+	///
+	/// [`EnvNaming::from_pkg`]: crate::config::EnvNaming::from_pkg
+	///
+	/// # Errors
+	///
+	/// Returns [`Error::Config`] listing every problem if the configuration is invalid, or if it
+	/// was already read with env vars named differently than [`EnvNaming::from_pkg`] would.
+	pub fn new<R>(svc: R) -> Result<Self>
 	where
-		R::Server: Service<Request<Body>, Error = Infallible> + Clone + Send + Sync + 'static,
+		R: crate::Service + 'static,
+		R::Server: Service<Request<Body>, Error = Infallible>
+			+ NamedService
+			+ Clone
+			+ Send
+			+ Sync
+			+ 'static,
 		<R::Server as Service<Request<Body>>>::Response: axum::response::IntoResponse,
 		<R::Server as Service<Request<Body>>>::Future: Send + 'static,
 	{
-		crate::tracing::init(&R::INFO);
-		crate::config::config();
+		Ok(Self::init(R::INFO)?.with_runesys_service(svc))
+	}
 
-		let mut s = Self::default().with_service(svc.new_server());
-		s.grpc = add_reflection_service::<R>(s.grpc).unwrap();
-		s
+	/// Initialize tracing and load config for a process without a gRPC server
+	///
+	/// A worker runs setup steps and background tasks, and serves HTTP if [`with_http`] is used.
+	/// Without HTTP, it exits once every background task has finished.
+	///
+	/// [`with_http`]: ServiceBuilder::with_http
+	/// [`EnvNaming::from_pkg`]: crate::config::EnvNaming::from_pkg
+	///
+	/// # Errors
+	///
+	/// Returns [`Error::Config`] listing every problem if the configuration is invalid, or if it
+	/// was already read with env vars named differently than [`EnvNaming::from_pkg`] would.
+	pub fn worker(info: ServiceInfo) -> Result<Self> {
+		Self::init(info)
+	}
+
+	/// Register another runesys [`Service`](crate::Service) on the same gRPC server
+	///
+	/// Its file descriptor set is merged into the reflection service, and it gets its own status
+	/// in the health service. The process keeps the [`ServiceInfo`] it was built with.
+	/// The server is built when the service is run, with the final [`GrpcOptions`].
+	#[must_use]
+	pub fn with_runesys_service<S>(mut self, svc: S) -> Self
+	where
+		S: crate::Service + 'static,
+		S::Server: Service<Request<Body>, Error = Infallible>
+			+ NamedService
+			+ Clone
+			+ Send
+			+ Sync
+			+ 'static,
+		<S::Server as Service<Request<Body>>>::Response: axum::response::IntoResponse,
+		<S::Server as Service<Request<Body>>>::Future: Send + 'static,
+	{
+		self.grpc_services.push(Box::new(move |routes, options| {
+			routes.add_service(S::configure_server(svc.new_server(), options))
+		}));
+		self.health_services.push(S::Server::NAME);
+		#[cfg(feature = "reflection")]
+		if !S::FILE_DESCRIPTOR_SET.is_empty() {
+			self.reflection
+				.push((S::FILE_DESCRIPTOR_SET, S::Server::NAME));
+		}
+		self
 	}
-}
 
-impl<R> ServiceBuilder<R>
-where
-	R: crate::Service + 'static,
-	R::Server: NamedService,
-{
 	/// Register a tonic gRPC service
+	///
+	/// The service gets its own status in the health service. Message size limits and compression
+	/// from [`GrpcOptions`] are not applied to it; use
+	/// [`with_configured_service`](Self::with_configured_service) or
+	/// [`configure_server!`](crate::configure_server) for that.
+	#[must_use]
 	pub fn with_service<S>(mut self, svc: S) -> Self
 	where
 		S: Service<Request<Body>, Error = Infallible>
@@ -119,24 +262,149 @@ where
 		S::Response: axum::response::IntoResponse,
 		S::Future: Send + 'static,
 	{
-		self.grpc = self.grpc.add_service(svc);
+		self.grpc_services
+			.push(Box::new(move |routes, _| routes.add_service(svc)));
+		self.health_services.push(S::NAME);
+		self
+	}
+
+	/// [Register](Self::with_service) a tonic gRPC service, configured with the final
+	/// [`GrpcOptions`] when the service is run
+	///
+	/// Like the servers of [`with_runesys_service`](Self::with_runesys_service), it gets the
+	/// message size limits and compression set in the config or by
+	/// [`with_grpc_options`](Self::with_grpc_options).
+	#[must_use]
+	pub fn with_configured_service<S>(mut self, svc: S) -> Self
+	where
+		S: Service<Request<Body>, Error = Infallible>
+			+ ConfigureServer
+			+ NamedService
+			+ Clone
+			+ Send
+			+ Sync
+			+ 'static,
+		S::Response: axum::response::IntoResponse,
+		S::Future: Send + 'static,
+	{
+		self.grpc_services.push(Box::new(move |routes, options| {
+			routes.add_service(svc.configure(options))
+		}));
+		self.health_services.push(S::NAME);
+		self
+	}
+
+	/// Wrap the registered gRPC services in `layer`, e.g. for auth, rate limiting or metrics
+	///
+	/// Layers run inside the built-in tracing and extension layers, so requests are traced and
+	/// carry the shared extensions; the first layer added is the outermost. The health and
+	/// reflection services are not wrapped.
+	#[must_use]
+	pub fn with_grpc_layer<L>(mut self, layer: L) -> Self
+	where
+		L: Layer<Route> + Clone + Send + Sync + 'static,
+		L::Service: Service<axum::extract::Request> + Clone + Send + Sync + 'static,
+		<L::Service as Service<axum::extract::Request>>::Response: IntoResponse + 'static,
+		<L::Service as Service<axum::extract::Request>>::Error: Into<Infallible> + 'static,
+		<L::Service as Service<axum::extract::Request>>::Future: Send + 'static,
+	{
+		self.grpc_layers
+			.push(Box::new(move |router| router.layer(layer)));
+		self
+	}
+
+	/// Adjust the gRPC server settings, starting from those in the config
+	///
+	/// For example, `with_grpc_options(|opts| opts.max_decoding_message_size(16 << 20))`.
+	#[must_use]
+	pub fn with_grpc_options(mut self, f: impl FnOnce(GrpcOptions) -> GrpcOptions) -> Self {
+		self.grpc_options = f(self.grpc_options);
+		self
+	}
+
+	/// Accept gRPC-Web requests from browsers, over HTTP/1.1 and HTTP/2
+	///
+	/// Every registered gRPC service, including health and reflection, is translated. `cors`
+	/// answers preflight requests and decides which origins may call the services; start from
+	/// [`grpc_web_cors`] and set the allowed origins.
+	#[cfg(feature = "grpc-web")]
+	#[must_use]
+	pub fn with_grpc_web(mut self, cors: CorsLayer) -> Self {
+		self.grpc_web = Some(cors);
 		self
 	}
 
 	/// Add an HTTP endpoint alongside gRPC
+	///
+	/// Routers from repeated calls, e.g. by modules, are merged.
+	///
+	/// # Panics
+	///
+	/// Panics if the routers have overlapping routes.
 	#[cfg(feature = "http")]
+	#[must_use]
 	pub fn with_http<T>(mut self, router: T) -> Self
 	where
 		T: Send + 'static,
 		axum::Router: From<T>,
 	{
-		self.http = Some(axum::Router::from(router));
+		let router = axum::Router::from(router);
+		self.http = Some(match self.http.take() {
+			Some(http) => http.merge(router),
+			None => router,
+		});
+		self
+	}
+
+	/// Wrap the HTTP router in `layer`, e.g. for auth, rate limiting or metrics
+	///
+	/// Layers run inside the built-in tracing and extension layers, so requests are traced and
+	/// carry the shared extensions; the first layer added is the outermost.
+	#[cfg(feature = "http")]
+	#[must_use]
+	pub fn with_http_layer<L>(mut self, layer: L) -> Self
+	where
+		L: Layer<Route> + Clone + Send + Sync + 'static,
+		L::Service: Service<axum::extract::Request> + Clone + Send + Sync + 'static,
+		<L::Service as Service<axum::extract::Request>>::Response: IntoResponse + 'static,
+		<L::Service as Service<axum::extract::Request>>::Error: Into<Infallible> + 'static,
+		<L::Service as Service<axum::extract::Request>>::Future: Send + 'static,
+	{
+		self.http_layers
+			.push(Box::new(move |router| router.layer(layer)));
+		self
+	}
+
+	/// Serve gRPC on a listener the caller already bound, instead of `address`:`grpc_port`
+	///
+	/// Takes precedence over [`grpc_socket`](crate::config::Config::grpc_socket). Binding port 0
+	/// lets tests run without fixed ports.
+	#[must_use]
+	pub fn with_grpc_listener(mut self, listener: TcpListener) -> Self {
+		self.grpc_listener = Some(listener);
+		self
+	}
+
+	/// Serve HTTP on a listener the caller already bound, instead of `address`:`http_port`
+	///
+	/// Takes precedence over [`http_socket`](crate::config::Config::http_socket).
+	#[cfg(feature = "http")]
+	#[must_use]
+	pub fn with_http_listener(mut self, listener: TcpListener) -> Self {
+		self.http_listener = Some(listener);
 		self
 	}
 
 	/// Add postgres database connection
+	///
+	/// Connecting and running `init` is the `postgres` setup step, which aborts startup on failure.
+	/// Once connected, the pool is checked every 10 seconds by the `postgres` health check.
+	///
+	/// # Errors
+	///
+	/// Returns [`Error::Config`] if no Postgres URL is configured.
 	#[cfg(feature = "db")]
-	pub fn with_pg<F, Fut>(mut self, init: F) -> Result<Self>
+	pub fn with_pg<F, Fut>(self, init: F) -> Result<Self>
 	where
 		F: FnOnce(PgPool) -> Fut + 'static,
 		Fut: Future<Output = std::result::Result<(), MigrateError>>,
@@ -148,108 +416,1004 @@ where
 			.map(Url::as_str)
 			.ok_or(Error::Config("Postgres URL not set".to_string()))?;
 
-		Ok(self.with_setup_task(Box::pin(async move {
+		Ok(self.with_setup_step(SetupStep::new("postgres", async move {
 			let pg_pool = sqlx::postgres::PgPoolOptions::new()
 				.max_connections(5)
 				.connect(postgres_url)
 				.await?;
 			init(pg_pool.clone()).await?;
 
-			let apply: ApplyFn<Self> = Box::new(move |mut sb| {
-				sb.pg_pool = Some(pg_pool);
-				sb
+			let apply: ApplyFn<Self> = Box::new(move |sb| {
+				let pool = pg_pool.clone();
+				sb.with_extension(pg_pool)
+					.with_health_check(HealthCheck::new(
+						"postgres",
+						Duration::from_secs(10),
+						move || {
+							let pool = pool.clone();
+							async move {
+								sqlx::query("SELECT 1").execute(&pool).await?;
+								Ok(())
+							}
+						},
+					))
 			});
 			Ok(Some(apply))
 		})))
 	}
 
-	pub fn with_setup_task(mut self, f: SetupTask<Self>) -> Self {
-		self.setup_tasks.push(Box::pin(f));
+	/// Add a reusable [`Module`]
+	///
+	/// # Errors
+	///
+	/// Returns [`Error::Config`] if the module's settings are invalid, or the error of
+	/// [`Module::register`].
+	pub fn with_module(self, module: impl Module) -> Result<Self> {
+		info!("Adding module {}", module.name());
+		let config = crate::config::extract_section(module.name())?;
+		module.register(self, config)
+	}
+
+	/// Share `value` with every gRPC and HTTP handler
+	///
+	/// It is cloned into the extensions of each request, so handlers can take it from
+	/// `request.extensions()` or with axum's `Extension` extractor. A later value of the same type
+	/// replaces an earlier one.
+	#[must_use]
+	pub fn with_extension<T>(mut self, value: T) -> Self
+	where
+		T: Clone + Send + Sync + 'static,
+	{
+		self.extensions.insert(value);
+		self
+	}
+
+	/// Share a value produced by a setup step with every gRPC and HTTP handler
+	///
+	/// `init` runs as the setup step `name` and aborts startup if it fails; use
+	/// [`SetupStep::extension`] for dependencies, a timeout or another failure policy.
+	#[must_use]
+	pub fn with_extension_step<T, Fut>(self, name: impl Into<Cow<'static, str>>, init: Fut) -> Self
+	where
+		T: Clone + Send + Sync + 'static,
+		Fut: Future<Output = Result<T>> + 'static,
+	{
+		self.with_setup_step(SetupStep::extension(name, init))
+	}
+
+	/// Probe a dependency periodically while the service runs
+	///
+	/// Checks start once setup has completed. While any of them is failing the service and every
+	/// registered gRPC service are reported as `NOT_SERVING`; the latest results are listed on the
+	/// admin server's `/checks`.
+	#[must_use]
+	pub fn with_health_check(mut self, check: HealthCheck) -> Self {
+		self.health_checks.push(check);
 		self
 	}
 
-	pub fn with_task<Fut>(mut self, f: Fut) -> Self
+	/// Run an anonymous setup step before serving, aborting startup if it fails
+	#[must_use]
+	pub fn with_setup_task(self, f: SetupTask<Self>) -> Self {
+		let name = format!("setup-{}", self.setup_steps.len());
+		self.with_setup_step(SetupStep::new(name, f))
+	}
+
+	/// Run a named setup step before serving
+	///
+	/// Steps run concurrently unless they declare dependencies on each other.
+	#[must_use]
+	pub fn with_setup_step(mut self, step: SetupStep<Self>) -> Self {
+		self.setup_steps.push(step);
+		self
+	}
+
+	/// Run a background task, which is aborted as soon as the service shuts down
+	///
+	/// If the task fails the service shuts down.
+	#[must_use]
+	pub fn with_task<Fut>(self, f: Fut) -> Self
+	where
+		Fut: Future<Output = Result<()>> + Send + 'static,
+	{
+		self.with_cancellable_task(Duration::ZERO, |_| f)
+	}
+
+	/// Run a background task that is told when the service shuts down
+	///
+	/// The task is handed a [`CancellationToken`] that is cancelled once in-flight requests have
+	/// drained, and is then given `shutdown_timeout` to finish its current unit of work before it
+	/// is aborted. If the task fails the service shuts down.
+	#[must_use]
+	pub fn with_cancellable_task<F, Fut>(self, shutdown_timeout: Duration, f: F) -> Self
 	where
+		F: FnOnce(CancellationToken) -> Fut + Send + 'static,
 		Fut: Future<Output = Result<()>> + Send + 'static,
 	{
-		self.handles.push(tokio::spawn(f));
+		let name = format!("task-{}", self.tasks.len());
+		self.with_supervised_task(Task::once(name, f).shutdown_timeout(shutdown_timeout))
+	}
+
+	/// Run a named background task under the supervisor, restarted according to its policy
+	#[must_use]
+	pub fn with_supervised_task(mut self, task: Task) -> Self {
+		self.tasks.push(task);
+		self
+	}
+
+	/// Run `f` once setup has completed, before background tasks start and anything is served
+	///
+	/// If it fails, startup is aborted.
+	#[must_use]
+	pub fn on_setup_complete<F, Fut>(mut self, f: F) -> Self
+	where
+		F: FnOnce() -> Fut + 'static,
+		Fut: Future<Output = Result<()>> + 'static,
+	{
+		self.hooks
+			.setup_complete
+			.push(Box::new(move |()| Box::pin(f())));
+		self
+	}
+
+	/// Run `f` with the failed steps when setup is aborted
+	#[must_use]
+	pub fn on_setup_failed<F>(mut self, f: F) -> Self
+	where
+		F: FnOnce(&SetupError) + 'static,
+	{
+		self.hooks.setup_failed.push(Box::new(f));
+		self
+	}
+
+	/// Run `f` with the bound addresses once the servers are accepting connections
+	///
+	/// With systemd, readiness is reported after every `on_ready` hook has succeeded. If one
+	/// fails, the service shuts down.
+	#[must_use]
+	pub fn on_ready<F, Fut>(mut self, f: F) -> Self
+	where
+		F: FnOnce(Ready) -> Fut + 'static,
+		Fut: Future<Output = Result<()>> + 'static,
+	{
+		self.hooks
+			.ready
+			.push(Box::new(move |ready| Box::pin(f(ready))));
+		self
+	}
+
+	/// Run `f` when shutdown begins, once health reports `NOT_SERVING` and before in-flight
+	/// requests are drained
+	///
+	/// Failures are logged. Hooks still running after [`shutdown_timeout`] seconds are abandoned.
+	///
+	/// [`shutdown_timeout`]: crate::config::Config::shutdown_timeout
+	#[must_use]
+	pub fn on_shutdown<F, Fut>(mut self, f: F) -> Self
+	where
+		F: FnOnce() -> Fut + 'static,
+		Fut: Future<Output = Result<()>> + 'static,
+	{
+		self.hooks.shutdown.push(Box::new(move |()| Box::pin(f())));
+		self
+	}
+
+	/// Run `f` once shutdown has completed and every background task has stopped
+	///
+	/// Failures are logged. Hooks still running after [`shutdown_timeout`] seconds are abandoned.
+	///
+	/// [`shutdown_timeout`]: crate::config::Config::shutdown_timeout
+	#[must_use]
+	pub fn on_stopped<F, Fut>(mut self, f: F) -> Self
+	where
+		F: FnOnce() -> Fut + 'static,
+		Fut: Future<Output = Result<()>> + 'static,
+	{
+		self.hooks.stopped.push(Box::new(move |()| Box::pin(f())));
 		self
 	}
 
 	/// Build and run gRPC + optional HTTP + report
+	///
+	/// The gRPC server, with health and reflection, is only started if a gRPC service was
+	/// registered. With [`single_port`] set, gRPC and HTTP are both served on `grpc_port`.
+	///
+	/// Setup steps run first; if one that aborts startup fails, nothing is served. If only steps
+	/// that degrade health failed, the service starts as `NOT_SERVING`.
+	/// Background tasks are started once the setup steps have completed. The service runs until a
+	/// server exits, a background task fails in a way its policy does not recover from, or the
+	/// process receives `SIGINT`/`SIGTERM`.
+	/// On shutdown the health status is flipped to `NOT_SERVING`, health checks stop, the
+	/// [`on_shutdown`] hooks run, the listeners stop accepting connections, in-flight requests are
+	/// given [`shutdown_timeout`] seconds to drain, and the remaining background tasks are
+	/// cancelled and awaited. The shutdown and stopped hooks are each given [`shutdown_timeout`]
+	/// seconds too. The admin server, if [`admin_port`] is set, runs from before setup until
+	/// everything else has stopped.
+	/// While running, the configuration is [reloaded](crate::config::reload) on `SIGHUP` and, if
+	/// [`config_reload_interval`] is set, when the config file changes.
+	///
+	/// Lifecycle hooks run in the order they were added: [`on_setup_failed`] when setup is aborted,
+	/// [`on_setup_complete`] before background tasks start, [`on_ready`] once the listeners are
+	/// bound, [`on_shutdown`] when shutdown begins and [`on_stopped`] once it has completed.
+	/// Once setup has completed, a failure to start, e.g. a failing hook or a listener that cannot
+	/// be bound, goes through the same shutdown, so [`on_shutdown`] and [`on_stopped`] still run.
+	///
+	/// [`on_setup_failed`]: ServiceBuilder::on_setup_failed
+	/// [`on_setup_complete`]: ServiceBuilder::on_setup_complete
+	/// [`on_ready`]: ServiceBuilder::on_ready
+	/// [`on_shutdown`]: ServiceBuilder::on_shutdown
+	/// [`on_stopped`]: ServiceBuilder::on_stopped
+	///
+	/// [`single_port`]: crate::config::Config::single_port
+	/// [`shutdown_timeout`]: crate::config::Config::shutdown_timeout
+	/// [`admin_port`]: crate::config::Config::admin_port
+	/// [`config_reload_interval`]: crate::config::Config::config_reload_interval
+	///
+	/// # Errors
+	///
+	/// Returns [`Error::Setup`] listing the failed steps if setup was aborted, [`Error::Config`] if
+	/// gRPC listener settings are combined with `single_port` or a server's port is 0, or
+	/// [`Error::Reflection`] if a file descriptor set cannot be decoded. Otherwise returns the
+	/// error of the server, background task or lifecycle hook that caused the service to stop.
+	/// Failed background tasks are reported as [`Error::Task`].
+	///
+	/// # Panics
+	///
+	/// Panics if there is nothing to run.
 	pub async fn run(mut self) -> Result<()> {
 		let config = crate::config::config();
 
-		let patches = futures::future::join_all(self.setup_tasks.drain(..))
-			.await
-			.into_iter()
-			.filter_map(|r| {
-				if let Err(r) = &r {
-					tracing::error!("{r}");
+		let (health_reporter, health_service) = health_reporter();
+		health_reporter
+			.set_service_status("", ServingStatus::NotServing)
+			.await;
+		let mut running = Running::default();
+		let admin_shutdown = CancellationToken::new();
+		let mut admin = JoinSet::new();
+		let admin_addr = self
+			.spawn_admin(
+				&mut admin,
+				&admin_shutdown,
+				&health_reporter,
+				&running.task_statuses,
+				&running.check_results,
+			)
+			.await?;
+
+		let setup = match crate::setup::run(std::mem::take(&mut self.setup_steps)).await {
+			Ok(setup) => setup,
+			Err(Error::Setup(err)) => {
+				for hook in std::mem::take(&mut self.hooks.setup_failed) {
+					hook(&err);
 				}
-				r.ok()
-			})
-			.filter_map(|opt| opt);
-		self = patches.fold(self, |acc, patch| patch(acc));
+				return Err(Error::Setup(err));
+			}
+			Err(err) => return Err(err),
+		};
+		self = setup
+			.patches
+			.into_iter()
+			.fold(self, |acc, patch| patch(acc));
+
+		let res = self
+			.serve(
+				&mut running,
+				&health_reporter,
+				health_service,
+				admin_addr,
+				setup.degraded.len(),
+			)
+			.await;
+
+		running.checks.shutdown().await;
+		self.set_health(&health_reporter, ServingStatus::NotServing)
+			.await;
+		#[cfg(feature = "systemd")]
+		crate::systemd::notify("STOPPING=1");
+		let timeout = Duration::from_secs(config.shutdown_timeout);
+		lifecycle::run_all(
+			std::mem::take(&mut self.hooks.shutdown),
+			"Shutdown",
+			timeout,
+		)
+		.await;
+		running.shutdown.cancel();
 
-		let health_reporter = {
-			let (hr, hs) = health_reporter();
-			hr.set_serving::<R::Server>().await;
+		if tokio::time::timeout(timeout, drain(&mut running.servers, "Server"))
+			.await
+			.is_err()
+		{
+			warn!("In-flight requests did not drain within {timeout:?}, aborting");
+			running.servers.abort_all();
+		}
+
+		running.tasks_shutdown.cancel();
+		drain(&mut running.tasks, "Background task").await;
+		admin_shutdown.cancel();
+		drain(&mut admin, "Admin server").await;
+		lifecycle::run_all(std::mem::take(&mut self.hooks.stopped), "Stopped", timeout).await;
+
+		res
+	}
+}
+
+/// What [`ServiceBuilder::run`] starts once setup has completed, stopped by its shutdown sequence
+/// whether or not everything could be started.
+#[derive(Default)]
+struct Running {
+	shutdown: CancellationToken,
+	tasks_shutdown: CancellationToken,
+	servers: JoinSet<Result<()>>,
+	tasks: JoinSet<Result<()>>,
+	checks: JoinSet<()>,
+	task_statuses: TaskStatuses,
+	check_results: CheckResults,
+}
+
+impl SetupStep<ServiceBuilder> {
+	/// A setup step sharing the value `init` produces with every gRPC and HTTP handler
+	///
+	/// See [`ServiceBuilder::with_extension`].
+	pub fn extension<T, Fut>(name: impl Into<Cow<'static, str>>, init: Fut) -> Self
+	where
+		T: Clone + Send + Sync + 'static,
+		Fut: Future<Output = Result<T>> + 'static,
+	{
+		Self::new(name, async move {
+			let value = init.await?;
+			let apply: ApplyFn<ServiceBuilder> = Box::new(move |sb| sb.with_extension(value));
+			Ok(Some(apply))
+		})
+	}
+}
+
+impl ServiceBuilder {
+	/// Start serving after setup, and wait until the service should stop
+	///
+	/// Everything started is recorded in `running`, so [`run`](Self::run) can stop it even if
+	/// this fails part way.
+	async fn serve(
+		&mut self,
+		running: &mut Running,
+		health_reporter: &HealthReporter,
+		health_service: HealthServer<impl Health>,
+		admin_addr: Option<ListenAddr>,
+		degraded: usize,
+	) -> Result<()> {
+		lifecycle::run(std::mem::take(&mut self.hooks.setup_complete), ()).await?;
+
+		running.checks = self
+			.start_health(health_reporter, &running.check_results, degraded)
+			.await;
+		running.tasks = crate::task::supervise(
+			std::mem::take(&mut self.tasks),
+			&running.tasks_shutdown,
+			&running.task_statuses,
+		);
+
+		self.extensions.insert(health_reporter.clone());
+		crate::config::spawn_watcher(running.shutdown.clone());
+		let mut ready = self
+			.spawn_servers(
+				&mut running.servers,
+				&running.shutdown,
+				health_reporter,
+				health_service,
+			)
+			.await?;
+		ready.admin = admin_addr;
+
+		assert!(
+			!running.servers.is_empty() || !running.tasks.is_empty(),
+			"No services to run"
+		);
+
+		lifecycle::run(std::mem::take(&mut self.hooks.ready), ready).await?;
+		#[cfg(feature = "systemd")]
+		{
+			crate::systemd::notify("READY=1");
+			crate::systemd::watchdog(&running.shutdown);
+		}
+		wait_for_exit(&mut running.servers, &mut running.tasks).await
+	}
+
+	/// Set the overall status and that of every registered gRPC service.
+	async fn set_health(&self, health_reporter: &HealthReporter, status: ServingStatus) {
+		crate::health::set_all(health_reporter, &self.health_services, status).await;
+	}
+
+	/// Report the status after setup and start the health checks
+	///
+	/// With `degraded` failed setup steps the service stays `NOT_SERVING` whatever the checks say.
+	async fn start_health(
+		&mut self,
+		health_reporter: &HealthReporter,
+		results: &CheckResults,
+		degraded: usize,
+	) -> JoinSet<()> {
+		if degraded == 0 {
+			self.set_health(health_reporter, ServingStatus::Serving)
+				.await;
+		} else {
+			warn!("{degraded} setup step(s) failed, starting as NOT_SERVING");
+			self.set_health(health_reporter, ServingStatus::NotServing)
+				.await;
+		}
+
+		crate::health::supervise(
+			std::mem::take(&mut self.health_checks),
+			results,
+			health_reporter.clone(),
+			self.health_services.clone(),
+			degraded == 0,
+		)
+	}
+
+	/// Spawn the admin server if [`admin_port`] is set
+	///
+	/// [`admin_port`]: crate::config::Config::admin_port
+	async fn spawn_admin(
+		&self,
+		admin: &mut JoinSet<Result<()>>,
+		shutdown: &CancellationToken,
+		health_reporter: &HealthReporter,
+		tasks: &TaskStatuses,
+		checks: &CheckResults,
+	) -> Result<Option<ListenAddr>> {
+		let config = crate::config::config();
+		let Some(port) = config.admin_port else {
+			return Ok(None);
+		};
+		let listener = TcpListener::bind(SocketAddr::new(config.address, port)).await?;
+		let addr = listener.local_addr()?;
+
+		let router = crate::admin::router(
+			self.info,
+			health_reporter.clone(),
+			self.health_services.clone().into(),
+			tasks.clone(),
+			checks.clone(),
+		);
+		let signal = shutdown.clone().cancelled_owned();
+		let name = self.info.name;
+		admin.spawn(async move {
+			info!("{name} admin at {addr}");
+			Ok(axum::serve(listener, router)
+				.with_graceful_shutdown(signal)
+				.await?)
+		});
+		Ok(Some(ListenAddr::Tcp(addr)))
+	}
+
+	/// Spawn the gRPC and HTTP servers, on one port if [`single_port`] is set
+	///
+	/// [`single_port`]: crate::config::Config::single_port
+	async fn spawn_servers(
+		&mut self,
+		servers: &mut JoinSet<Result<()>>,
+		shutdown: &CancellationToken,
+		#[cfg_attr(not(feature = "http"), allow(unused_variables))]
+		health_reporter: &HealthReporter,
+		health_service: HealthServer<impl Health>,
+	) -> Result<Ready> {
+		let grpc = self.grpc_router(health_service)?;
+		#[cfg(feature = "http")]
+		let http = self.http_router(health_reporter)?;
 
-			self.grpc = self.grpc.add_service(hs);
-			hr
+		#[cfg(feature = "systemd")]
+		let activated = crate::systemd::listeners()?;
+		let config = crate::config::config();
+		let grpc_listener = self.grpc_listener.take().map(Listen::Listener);
+		#[cfg(feature = "systemd")]
+		let grpc_listener = grpc_listener.or(activated.grpc);
+		let grpc_listen = Listen::resolve(
+			grpc_listener,
+			#[cfg(unix)]
+			config.grpc_socket.as_ref(),
+			config.grpc_port,
+		);
+		#[cfg(feature = "http")]
+		if config.single_port
+			&& let (Some(grpc), Some(http)) = (&grpc, &http)
+		{
+			if self.grpc_options.has_listener_settings() {
+				return Err(Error::Config(
+					"gRPC keepalive, concurrency limit and timeout cannot be used with `single_port`"
+						.into(),
+				));
+			}
+			let router = axum::Router::new().fallback_service(Multiplex {
+				grpc: grpc.clone(),
+				http: http.clone(),
+			});
+			check_port(&grpc_listen, "grpc_port")?;
+			let listener = grpc_listen.bind().await?;
+			let addr = listener.local_addr()?;
+			self.spawn_http(servers, shutdown, listener, "gRPC+HTTP", router)?;
+			return Ok(Ready {
+				grpc: Some(addr.clone()),
+				http: Some(addr),
+				admin: None,
+			});
+		}
+
+		let mut ready = Ready {
+			grpc: None,
+			#[cfg(feature = "http")]
+			http: None,
+			admin: None,
+		};
+		if let Some(grpc) = grpc {
+			check_port(&grpc_listen, "grpc_port")?;
+			let listener = grpc_listen.bind().await?;
+			ready.grpc = Some(listener.local_addr()?);
+			self.spawn_grpc(servers, shutdown, listener, grpc)?;
+		}
+		#[cfg(feature = "http")]
+		if let Some(http) = http {
+			let http_listener = self.http_listener.take().map(Listen::Listener);
+			#[cfg(feature = "systemd")]
+			let http_listener = http_listener.or(activated.http);
+			let listen = Listen::resolve(
+				http_listener,
+				#[cfg(unix)]
+				config.http_socket.as_ref(),
+				config.http_port,
+			);
+			check_port(&listen, "http_port")?;
+			let listener = listen.bind().await?;
+			ready.http = Some(listener.local_addr()?);
+			self.spawn_http(servers, shutdown, listener, "HTTP", http)?;
+		}
+		Ok(ready)
+	}
+
+	/// The registered gRPC services with health, reflection and the gRPC layers.
+	#[cfg_attr(not(feature = "reflection"), allow(clippy::unnecessary_wraps))]
+	fn grpc_router(
+		&mut self,
+		health_service: HealthServer<impl Health>,
+	) -> Result<Option<axum::Router>> {
+		let services = std::mem::take(&mut self.grpc_services);
+		if services.is_empty() {
+			return Ok(None);
+		}
+		let grpc = services.into_iter().fold(Routes::default(), |routes, add| {
+			add(routes, &self.grpc_options)
+		});
+		let grpc = Routes::from(apply_layers(
+			grpc.into_axum_router(),
+			std::mem::take(&mut self.grpc_layers),
+		));
+		#[cfg(feature = "reflection")]
+		let grpc = if self.reflection.is_empty() || !reflection_enabled() {
+			grpc
+		} else {
+			let (v1, v1alpha) = reflection_service(&self.reflection)?;
+			grpc.add_service(v1).add_service(v1alpha)
 		};
 
 		let sb = tower::ServiceBuilder::new()
 			.layer(TraceLayer::new_for_grpc())
-			.layer(AddExtensionLayer::new(health_reporter.clone()));
-		#[cfg(feature = "db")]
-		let sb = sb.layer(option_layer(
-			self.pg_pool
-				.as_ref()
-				.map(|pg| AddExtensionLayer::new(pg.clone())),
-		));
+			.map_request(add_extensions(self.extensions.clone()));
+		#[cfg(feature = "tls")]
+		let sb = sb.map_request(crate::tls::grpc_peer_identity::<axum::body::Body>);
 
-		// gRPC builder
-		let grpc_builder = Server::builder().layer(sb).add_routes(self.grpc);
-		let grpc_addr = SocketAddr::new(config.address, config.grpc_port);
-		self.handles.push(tokio::spawn(async move {
-			info!("{} gRPC at {grpc_addr}", R::INFO.name);
-			Ok(grpc_builder.serve(grpc_addr).await?)
-		}));
+		let router = grpc
+			.add_service(health_service)
+			.into_axum_router()
+			.layer(sb);
+		#[cfg(feature = "grpc-web")]
+		let router = match &self.grpc_web {
+			Some(cors) => router
+				.layer(tonic_web::GrpcWebLayer::new())
+				.layer(cors.clone()),
+			None => router,
+		};
+		Ok(Some(router))
+	}
 
-		// combine with HTTP if present
-		#[cfg(feature = "http")]
-		if let Some(router) = self.http {
-			let sb = tower::ServiceBuilder::new()
-				.layer(TraceLayer::new_for_http())
-				.layer(AddExtensionLayer::new(health_reporter));
+	/// The HTTP router with the extension layers, the configured HTTP middleware and, if
+	/// enabled, the health probes.
+	#[cfg(feature = "http")]
+	fn http_router(&mut self, health_reporter: &HealthReporter) -> Result<Option<axum::Router>> {
+		let Some(router) = self.http.take() else {
+			return Ok(None);
+		};
+
+		let router = apply_layers(router, std::mem::take(&mut self.http_layers));
+		let router = router.layer(tower::util::MapRequestLayer::new(add_extensions(
+			self.extensions.clone(),
+		)));
+		let config = crate::config::config();
+		let router = crate::http::layers(router, config)?;
+		Ok(Some(if config.http_health_probes {
+			router.merge(crate::health::router(
+				health_reporter.clone(),
+				self.health_services.clone().into(),
+			))
+		} else {
+			router
+		}))
+	}
 
-			#[cfg(feature = "db")]
-			let sb = sb.layer(option_layer(
-				self.pg_pool
-					.as_ref()
-					.map(|pg| AddExtensionLayer::new(pg.clone())),
-			));
-			let router = router.layer(sb);
+	#[cfg_attr(not(feature = "tls"), allow(clippy::unnecessary_wraps))]
+	fn spawn_grpc(
+		&self,
+		servers: &mut JoinSet<Result<()>>,
+		shutdown: &CancellationToken,
+		listener: Bound,
+		router: axum::Router,
+	) -> Result<()> {
+		#[cfg(not(feature = "grpc-web"))]
+		let http1 = false;
+		#[cfg(feature = "grpc-web")]
+		let http1 = self.grpc_web.is_some();
+		#[cfg(feature = "tls")]
+		let tls = crate::tls::TlsAcceptor::from_config(
+			crate::config::config(),
+			if http1 {
+				&[b"h2", b"http/1.1"]
+			} else {
+				&[b"h2"]
+			},
+			shutdown,
+		)?;
+		let grpc_builder = self
+			.grpc_options
+			.server()
+			.accept_http1(http1)
+			.add_routes(Routes::from(router));
+		let signal = shutdown.clone().cancelled_owned();
+		let name = self.info.name;
+		servers.spawn(async move {
+			let listener = match listener {
+				#[cfg(unix)]
+				Bound::Unix(socket) => {
+					info!("{name} gRPC at {}", socket.display());
+					return Ok(grpc_builder
+						.serve_with_incoming_shutdown(socket.into_stream(), signal)
+						.await?);
+				}
+				Bound::Tcp(listener) => listener,
+			};
+			let grpc_addr = listener.local_addr()?;
+
+			#[cfg(feature = "tls")]
+			if let Some(tls) = tls {
+				let listener = TlsListener::new(listener, tls)?;
+				info!("{name} gRPC (TLS) at {grpc_addr}");
+				return Ok(grpc_builder
+					.serve_with_incoming_shutdown(listener.into_stream(), signal)
+					.await?);
+			}
+
+			info!("{name} gRPC at {grpc_addr}");
+			let incoming = TcpIncoming::from(listener).with_nodelay(Some(true));
+			Ok(grpc_builder
+				.serve_with_incoming_shutdown(incoming, signal)
+				.await?)
+		});
+		Ok(())
+	}
+
+	#[cfg(feature = "http")]
+	#[cfg_attr(not(feature = "tls"), allow(clippy::unnecessary_wraps))]
+	fn spawn_http(
+		&self,
+		servers: &mut JoinSet<Result<()>>,
+		shutdown: &CancellationToken,
+		listener: Bound,
+		what: &'static str,
+		router: axum::Router,
+	) -> Result<()> {
+		#[cfg(feature = "tls")]
+		let tls = crate::tls::TlsAcceptor::from_config(
+			crate::config::config(),
+			&[b"h2", b"http/1.1"],
+			shutdown,
+		)?;
+		let signal = shutdown.clone().cancelled_owned();
+		let name = self.info.name;
+		servers.spawn(async move {
+			let listener = match listener {
+				#[cfg(unix)]
+				Bound::Unix(socket) => {
+					info!("{name} {what} at {}", socket.display());
+					return Ok(axum::serve(socket, router)
+						.with_graceful_shutdown(signal)
+						.await?);
+				}
+				Bound::Tcp(listener) => listener,
+			};
+			let http_addr = listener.local_addr()?;
 
-			let http_addr = SocketAddr::new(config.address, config.http_port);
-			self.handles.push(tokio::spawn(async move {
-				info!("{} HTTP at {http_addr}", R::INFO.name);
-				Ok(axum::serve(TcpListener::bind(http_addr).await?, router).await?)
-			}));
+			#[cfg(feature = "tls")]
+			if let Some(tls) = tls {
+				let listener = TlsListener::new(listener, tls)?;
+				info!("{name} {what} (TLS) at {http_addr}");
+				return Ok(axum::serve(listener, crate::tls::HttpPeerIdentity(router))
+					.with_graceful_shutdown(signal)
+					.await?);
+			}
+
+			info!("{name} {what} at {http_addr}");
+			Ok(axum::serve(listener, router)
+				.with_graceful_shutdown(signal)
+				.await?)
+		});
+		Ok(())
+	}
+}
+
+/// Wrap `router` in `layers`, the first outermost.
+fn apply_layers(router: axum::Router, layers: Vec<LayerFn>) -> axum::Router {
+	layers
+		.into_iter()
+		.rev()
+		.fold(router, |router, layer| layer(router))
+}
+
+/// Insert a copy of `extensions` into each request.
+fn add_extensions(
+	extensions: Extensions,
+) -> impl Fn(axum::extract::Request) -> axum::extract::Request + Clone {
+	move |mut req| {
+		req.extensions_mut().extend(extensions.clone());
+		req
+	}
+}
+
+/// Sends requests with a gRPC `content-type` to `grpc` and everything else to `http`.
+#[cfg(feature = "http")]
+#[derive(Clone)]
+struct Multiplex {
+	grpc: axum::Router,
+	http: axum::Router,
+}
+
+#[cfg(feature = "http")]
+impl Service<axum::extract::Request> for Multiplex {
+	type Response = axum::response::Response;
+	type Error = Infallible;
+	type Future = <axum::Router as Service<axum::extract::Request>>::Future;
+
+	fn poll_ready(
+		&mut self,
+		_cx: &mut std::task::Context<'_>,
+	) -> std::task::Poll<std::result::Result<(), Self::Error>> {
+		std::task::Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, req: axum::extract::Request) -> Self::Future {
+		if is_grpc(&req) {
+			self.grpc.call(req)
+		} else {
+			self.http.call(req)
+		}
+	}
+}
+
+/// Whether `req` is a gRPC (or gRPC-Web) call, or a CORS preflight for a gRPC-Web call.
+#[cfg(feature = "http")]
+fn is_grpc(req: &axum::extract::Request) -> bool {
+	use axum::http::header;
+
+	let headers = req.headers();
+	let grpc = headers
+		.get(header::CONTENT_TYPE)
+		.is_some_and(|ct| ct.as_bytes().starts_with(b"application/grpc"));
+	#[cfg(feature = "grpc-web")]
+	let grpc = grpc
+		|| req.method() == axum::http::Method::OPTIONS
+			&& headers
+				.get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+				.and_then(|h| h.to_str().ok())
+				.is_some_and(|h| h.to_ascii_lowercase().contains("x-grpc-web"));
+	grpc
+}
+
+/// Whether [`grpc_reflection`](crate::config::Config::grpc_reflection) is on.
+#[cfg(feature = "reflection")]
+fn reflection_enabled() -> bool {
+	crate::config::config()
+		.grpc_reflection
+		.unwrap_or(cfg!(debug_assertions))
+}
+
+/// Build the v1 and v1alpha reflection services covering every registered file descriptor set.
+#[cfg(feature = "reflection")]
+fn reflection_service(
+	sets: &[(&'static [u8], &'static str)],
+) -> Result<(
+	tonic_reflection::pb::v1::server_reflection_server::ServerReflectionServer<
+		impl tonic_reflection::pb::v1::server_reflection_server::ServerReflection,
+	>,
+	tonic_reflection::pb::v1alpha::server_reflection_server::ServerReflectionServer<
+		impl tonic_reflection::pb::v1alpha::server_reflection_server::ServerReflection,
+	>,
+)> {
+	let builder = || {
+		sets.iter().fold(
+			tonic_reflection::server::Builder::configure(),
+			|builder, (set, name)| {
+				builder
+					.register_encoded_file_descriptor_set(set)
+					.with_service_name(*name)
+			},
+		)
+	};
+	Ok((builder().build_v1()?, builder().build_v1alpha()?))
+}
+
+/// Wait until a shutdown signal arrives, a server exits, or a background task fails.
+///
+/// Without servers, this also returns once every background task has finished.
+async fn wait_for_exit(
+	servers: &mut JoinSet<Result<()>>,
+	tasks: &mut JoinSet<Result<()>>,
+) -> Result<()> {
+	let signal = shutdown_signal();
+	tokio::pin!(signal);
+
+	loop {
+		if servers.is_empty() && tasks.is_empty() {
+			info!("All background tasks finished");
+			return Ok(());
 		}
 
-		if self.handles.is_empty() {
-			panic!("No services to run");
+		tokio::select! {
+			() = &mut signal => {
+				info!("Shutdown signal received");
+				return Ok(());
+			}
+			Some(res) = servers.join_next() => return joined(res),
+			Some(res) = tasks.join_next() => joined(res)?,
 		}
+	}
+}
 
-		let (res, _, remaining) = select_all(self.handles).await;
-		for handle in remaining {
-			handle.abort();
+/// Reject listening on the config port `key` if it is 0, as the server would get a random port.
+fn check_port(listen: &Listen, key: &str) -> Result<()> {
+	match listen {
+		Listen::Tcp(addr) if addr.port() == 0 => {
+			Err(Error::Config(format!("`{key}`: must not be 0")))
 		}
-		res.unwrap()?;
+		_ => Ok(()),
+	}
+}
 
-		Ok(())
+/// Wait for everything in `set` to finish, logging failures.
+async fn drain(set: &mut JoinSet<Result<()>>, what: &str) {
+	while let Some(res) = set.join_next().await {
+		if let Err(err) = joined(res) {
+			error!("{what} failed during shutdown: {err}");
+		}
+	}
+}
+
+fn joined(res: std::result::Result<Result<()>, JoinError>) -> Result<()> {
+	res.map_err(Error::from).and_then(|r| r)
+}
+
+/// Completes when the process receives `SIGINT` or, on Unix, `SIGTERM`.
+async fn shutdown_signal() {
+	let ctrl_c = async {
+		if let Err(err) = tokio::signal::ctrl_c().await {
+			error!("Failed to listen for SIGINT: {err}");
+			std::future::pending::<()>().await;
+		}
+	};
+
+	#[cfg(unix)]
+	let terminate = async {
+		match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+			Ok(mut signal) => {
+				signal.recv().await;
+			}
+			Err(err) => {
+				error!("Failed to listen for SIGTERM: {err}");
+				std::future::pending::<()>().await;
+			}
+		}
+	};
+	#[cfg(not(unix))]
+	let terminate = std::future::pending::<()>();
+
+	tokio::select! {
+		() = ctrl_c => {}
+		() = terminate => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use tonic::{Status, service::interceptor::InterceptedService};
+
+	use super::*;
+
+	#[cfg(feature = "http")]
+	fn request(method: &str, headers: &[(&str, &str)]) -> axum::extract::Request {
+		let mut req = axum::http::Request::builder()
+			.method(method)
+			.uri("/greeter.Greeter/Hello");
+		for (name, value) in headers {
+			req = req.header(*name, *value);
+		}
+		req.body(axum::body::Body::empty()).unwrap()
+	}
+
+	#[cfg(feature = "http")]
+	#[test]
+	fn routes_grpc_content_types_to_grpc() {
+		for content_type in [
+			"application/grpc",
+			"application/grpc+proto",
+			"application/grpc-web",
+			"application/grpc-web+proto",
+		] {
+			let req = request("POST", &[("content-type", content_type)]);
+			assert!(is_grpc(&req), "{content_type}");
+		}
+	}
+
+	#[cfg(feature = "http")]
+	#[test]
+	fn routes_plain_http_to_http() {
+		assert!(!is_grpc(&request("GET", &[])));
+		assert!(!is_grpc(&request(
+			"POST",
+			&[("content-type", "application/json")]
+		)));
+	}
+
+	#[cfg(feature = "http")]
+	#[test]
+	fn routes_grpc_web_preflights_to_grpc() {
+		let preflight = |requested| {
+			request(
+				"OPTIONS",
+				&[
+					("origin", "https://example.com"),
+					("access-control-request-method", "POST"),
+					("access-control-request-headers", requested),
+				],
+			)
+		};
+		assert_eq!(
+			is_grpc(&preflight("content-type,X-Grpc-Web")),
+			cfg!(feature = "grpc-web")
+		);
+		assert!(!is_grpc(&preflight("content-type")));
+		assert!(!is_grpc(&request("OPTIONS", &[])));
+	}
+
+	#[cfg(feature = "http")]
+	#[tokio::test]
+	async fn multiplexes_by_content_type() {
+		let mut multiplex = Multiplex {
+			grpc: axum::Router::new().fallback(|| async { "grpc" }),
+			http: axum::Router::new().fallback(|| async { "http" }),
+		};
+		for (req, expected) in [
+			(
+				request("POST", &[("content-type", "application/grpc")]),
+				"grpc",
+			),
+			(request("GET", &[]), "http"),
+		] {
+			let body = multiplex.call(req).await.unwrap().into_body();
+			let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+			assert_eq!(body, expected);
+		}
+	}
+
+	/// Servers from other crates cannot implement [`ConfigureServer`], but can still be registered.
+	#[allow(dead_code)]
+	fn registers_servers_from_other_crates(builder: ServiceBuilder) -> ServiceBuilder {
+		let (_, health) = tonic_health::server::health_reporter();
+		builder
+			.with_service(health.clone())
+			.with_service(InterceptedService::new(health, Ok::<_, Status>))
 	}
 }
@@ -0,0 +1,456 @@
+use std::{
+	borrow::Cow,
+	collections::HashSet,
+	fmt::{self, Display},
+	pin::Pin,
+	time::Duration,
+};
+
+use futures::{StreamExt, stream::FuturesUnordered};
+use thiserror::Error;
+use tracing::{error, info, warn};
+
+use crate::error::{Error, Result};
+
+type BoxFut<T> = Pin<Box<dyn Future<Output = T> + 'static>>;
+pub type ApplyFn<T> = Box<dyn FnOnce(T) -> T>;
+pub type SetupTask<T> = BoxFut<Result<Option<ApplyFn<T>>>>;
+
+/// What happens to startup when a setup step fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SetupPolicy {
+	/// Abort startup; `run` returns an [`Error::Setup`].
+	#[default]
+	Abort,
+	/// Start anyway, but report the service as `NOT_SERVING`.
+	Degrade,
+}
+
+/// A named setup step, run before the service starts serving
+pub struct SetupStep<T> {
+	name: Cow<'static, str>,
+	depends_on: Vec<Cow<'static, str>>,
+	timeout: Option<Duration>,
+	policy: SetupPolicy,
+	task: SetupTask<T>,
+}
+
+impl<T> SetupStep<T> {
+	/// Create a setup step; by default it has no dependencies or timeout and aborts startup on failure
+	pub fn new<Fut>(name: impl Into<Cow<'static, str>>, task: Fut) -> Self
+	where
+		Fut: Future<Output = Result<Option<ApplyFn<T>>>> + 'static,
+	{
+		Self {
+			name: name.into(),
+			depends_on: Vec::new(),
+			timeout: None,
+			policy: SetupPolicy::default(),
+			task: Box::pin(task),
+		}
+	}
+
+	/// Only start this step once the step called `name` has succeeded
+	#[must_use]
+	pub fn depends_on(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+		self.depends_on.push(name.into());
+		self
+	}
+
+	/// Fail the step if it does not complete within `timeout`
+	#[must_use]
+	pub fn timeout(mut self, timeout: Duration) -> Self {
+		self.timeout = Some(timeout);
+		self
+	}
+
+	/// Set what happens to startup when this step fails
+	#[must_use]
+	pub fn on_failure(mut self, policy: SetupPolicy) -> Self {
+		self.policy = policy;
+		self
+	}
+
+	#[must_use]
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+}
+
+/// Why a single setup step failed
+#[derive(Error, Debug)]
+pub enum StepError {
+	#[error(transparent)]
+	Failed(Error),
+	#[error("timed out after {0:?}")]
+	TimedOut(Duration),
+	#[error("dependency `{0}` failed")]
+	DependencyFailed(Cow<'static, str>),
+	#[error("depends on unknown step `{0}`")]
+	UnknownDependency(Cow<'static, str>),
+	#[error("dependency cycle")]
+	Cycle,
+	#[error("duplicate step name")]
+	Duplicate,
+}
+
+/// A setup step that failed, and why
+#[derive(Debug)]
+pub struct SetupFailure {
+	pub step: Cow<'static, str>,
+	pub policy: SetupPolicy,
+	pub error: StepError,
+}
+
+/// The setup steps that failed during startup
+#[derive(Error, Debug)]
+pub struct SetupError {
+	pub failures: Vec<SetupFailure>,
+}
+
+impl Display for SetupError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("setup failed")?;
+		for (i, failure) in self.failures.iter().enumerate() {
+			let sep = if i == 0 { ": " } else { "; " };
+			write!(f, "{sep}`{}` {}", failure.step, failure.error)?;
+		}
+		Ok(())
+	}
+}
+
+/// Outcome of the setup phase when startup was not aborted
+pub(crate) struct SetupOutcome<T> {
+	pub patches: Vec<ApplyFn<T>>,
+	pub degraded: Vec<SetupFailure>,
+}
+
+/// Run `steps`, each as soon as its dependencies have succeeded
+///
+/// Independent steps run concurrently. The first failure of an [`SetupPolicy::Abort`] step
+/// cancels the remaining steps.
+pub(crate) async fn run<T>(steps: Vec<SetupStep<T>>) -> Result<SetupOutcome<T>> {
+	let mut failures = validate(&steps);
+	if !failures.is_empty() {
+		return Err(SetupError { failures }.into());
+	}
+
+	let mut pending: Vec<SetupStep<T>> = steps;
+	let mut succeeded = HashSet::new();
+	let mut failed = HashSet::new();
+	let mut patches = Vec::new();
+	let mut running = FuturesUnordered::new();
+
+	loop {
+		let mut i = 0;
+		while i < pending.len() {
+			let step = &pending[i];
+			if let Some(dep) = step
+				.depends_on
+				.iter()
+				.find(|d| failed.contains(*d))
+				.cloned()
+			{
+				let step = pending.swap_remove(i);
+				warn!(step = %step.name, "Skipping setup step, dependency `{dep}` failed");
+				failed.insert(step.name.clone());
+				failures.push(SetupFailure {
+					error: StepError::DependencyFailed(dep),
+					step: step.name,
+					policy: step.policy,
+				});
+				i = 0;
+			} else if step.depends_on.iter().all(|d| succeeded.contains(d)) {
+				let step = pending.swap_remove(i);
+				running.push(start(step));
+			} else {
+				i += 1;
+			}
+		}
+
+		if failures.iter().any(|f| f.policy == SetupPolicy::Abort) {
+			return Err(SetupError { failures }.into());
+		}
+
+		let Some((name, policy, res)) = running.next().await else {
+			break;
+		};
+		match res {
+			Ok(patch) => {
+				info!(step = %name, "Setup step completed");
+				patches.extend(patch);
+				succeeded.insert(name);
+			}
+			Err(err) => {
+				error!(step = %name, "Setup step failed: {err}");
+				failed.insert(name.clone());
+				failures.push(SetupFailure {
+					step: name,
+					policy,
+					error: err,
+				});
+			}
+		}
+	}
+
+	// Steps still pending once nothing is running are waiting on each other.
+	failures.extend(pending.into_iter().map(|step| SetupFailure {
+		step: step.name,
+		policy: step.policy,
+		error: StepError::Cycle,
+	}));
+	if failures.iter().any(|f| f.policy == SetupPolicy::Abort) {
+		return Err(SetupError { failures }.into());
+	}
+
+	Ok(SetupOutcome {
+		patches,
+		degraded: failures,
+	})
+}
+
+/// Check for duplicate names and unknown dependencies before anything runs
+fn validate<T>(steps: &[SetupStep<T>]) -> Vec<SetupFailure> {
+	let mut names = HashSet::new();
+	let mut failures = Vec::new();
+
+	for step in steps {
+		if !names.insert(&step.name) {
+			failures.push(SetupFailure {
+				step: step.name.clone(),
+				policy: step.policy,
+				error: StepError::Duplicate,
+			});
+		}
+	}
+	for step in steps {
+		if let Some(dep) = step.depends_on.iter().find(|d| !names.contains(d)) {
+			failures.push(SetupFailure {
+				step: step.name.clone(),
+				policy: step.policy,
+				error: StepError::UnknownDependency(dep.clone()),
+			});
+		}
+	}
+
+	failures
+}
+
+async fn start<T>(
+	step: SetupStep<T>,
+) -> (
+	Cow<'static, str>,
+	SetupPolicy,
+	std::result::Result<Option<ApplyFn<T>>, StepError>,
+) {
+	let res = match step.timeout {
+		Some(timeout) => tokio::time::timeout(timeout, step.task)
+			.await
+			.map_err(|_| StepError::TimedOut(timeout))
+			.and_then(|r| r.map_err(StepError::Failed)),
+		None => step.task.await.map_err(StepError::Failed),
+	};
+	(step.name, step.policy, res)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, rc::Rc};
+
+	use tokio::time::{Instant, sleep};
+
+	use super::*;
+
+	type Log = Rc<RefCell<Vec<&'static str>>>;
+
+	/// A step that takes `duration`, logs its name and then fails if `fail` is set.
+	fn step(
+		log: &Log,
+		name: &'static str,
+		duration: Duration,
+		fail: bool,
+	) -> SetupStep<Vec<&'static str>> {
+		let log = log.clone();
+		SetupStep::new(name, async move {
+			sleep(duration).await;
+			log.borrow_mut().push(name);
+			if fail {
+				return Err(Error::Config(format!("{name} failed")));
+			}
+			let patch: ApplyFn<Vec<&'static str>> = Box::new(move |mut applied| {
+				applied.push(name);
+				applied
+			});
+			Ok(Some(patch))
+		})
+	}
+
+	fn errors(failures: &[SetupFailure]) -> Vec<(&str, String)> {
+		let mut errors: Vec<_> = failures
+			.iter()
+			.map(|f| (f.step.as_ref(), f.error.to_string()))
+			.collect();
+		errors.sort();
+		errors
+	}
+
+	fn setup_error(res: Result<SetupOutcome<Vec<&'static str>>>) -> SetupError {
+		match res {
+			Err(Error::Setup(err)) => err,
+			Err(err) => panic!("unexpected error: {err}"),
+			Ok(_) => panic!("setup unexpectedly succeeded"),
+		}
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn runs_steps_in_dependency_order() {
+		let log = Log::default();
+		let started = Instant::now();
+		let outcome = run(vec![
+			step(&log, "c", Duration::from_secs(1), false).depends_on("b"),
+			step(&log, "b", Duration::from_secs(1), false).depends_on("a"),
+			step(&log, "a", Duration::from_secs(3), false),
+			step(&log, "d", Duration::from_secs(2), false),
+		])
+		.await
+		.unwrap();
+
+		assert_eq!(*log.borrow(), ["d", "a", "b", "c"]);
+		assert_eq!(started.elapsed(), Duration::from_secs(5));
+		let applied = outcome
+			.patches
+			.into_iter()
+			.fold(Vec::new(), |acc, patch| patch(acc));
+		assert_eq!(applied, ["d", "a", "b", "c"]);
+		assert!(outcome.degraded.is_empty());
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn skips_dependents_of_a_failed_step() {
+		let log = Log::default();
+		let outcome = run(vec![
+			step(&log, "a", Duration::ZERO, true).on_failure(SetupPolicy::Degrade),
+			step(&log, "b", Duration::ZERO, false)
+				.depends_on("a")
+				.on_failure(SetupPolicy::Degrade),
+			step(&log, "c", Duration::ZERO, false)
+				.depends_on("b")
+				.on_failure(SetupPolicy::Degrade),
+			step(&log, "d", Duration::from_secs(1), false),
+		])
+		.await
+		.unwrap();
+
+		assert_eq!(*log.borrow(), ["a", "d"]);
+		assert_eq!(outcome.patches.len(), 1);
+		assert_eq!(
+			errors(&outcome.degraded),
+			[
+				("a", "config error: a failed".to_owned()),
+				("b", "dependency `a` failed".to_owned()),
+				("c", "dependency `b` failed".to_owned()),
+			]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn abort_failure_cancels_remaining_steps() {
+		let log = Log::default();
+		let started = Instant::now();
+		let err = setup_error(
+			run(vec![
+				step(&log, "a", Duration::from_secs(1), true),
+				step(&log, "b", Duration::from_secs(10), false),
+				step(&log, "c", Duration::ZERO, false).depends_on("a"),
+			])
+			.await,
+		);
+
+		assert_eq!(*log.borrow(), ["a"]);
+		assert_eq!(started.elapsed(), Duration::from_secs(1));
+		assert_eq!(
+			errors(&err.failures),
+			[
+				("a", "config error: a failed".to_owned()),
+				("c", "dependency `a` failed".to_owned()),
+			]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn degraded_dependent_of_an_aborting_step_still_aborts() {
+		let log = Log::default();
+		let err = setup_error(
+			run(vec![
+				step(&log, "a", Duration::ZERO, true).on_failure(SetupPolicy::Degrade),
+				step(&log, "b", Duration::ZERO, false).depends_on("a"),
+			])
+			.await,
+		);
+
+		assert_eq!(err.failures.len(), 2);
+		assert_eq!(err.failures[1].policy, SetupPolicy::Abort);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn times_out_slow_steps() {
+		let log = Log::default();
+		let outcome = run(vec![
+			step(&log, "slow", Duration::from_secs(5), false)
+				.timeout(Duration::from_secs(1))
+				.on_failure(SetupPolicy::Degrade),
+		])
+		.await
+		.unwrap();
+
+		assert!(log.borrow().is_empty());
+		assert_eq!(
+			errors(&outcome.degraded),
+			[("slow", "timed out after 1s".to_owned())]
+		);
+	}
+
+	#[tokio::test]
+	async fn detects_cycles() {
+		let log = Log::default();
+		let err = setup_error(
+			run(vec![
+				step(&log, "a", Duration::ZERO, false).depends_on("b"),
+				step(&log, "b", Duration::ZERO, false).depends_on("a"),
+				step(&log, "c", Duration::ZERO, false),
+			])
+			.await,
+		);
+
+		assert_eq!(*log.borrow(), ["c"]);
+		assert_eq!(
+			errors(&err.failures),
+			[
+				("a", "dependency cycle".to_owned()),
+				("b", "dependency cycle".to_owned()),
+			]
+		);
+	}
+
+	#[tokio::test]
+	async fn rejects_unknown_and_duplicate_steps_before_running() {
+		let log = Log::default();
+		let err = setup_error(
+			run(vec![
+				step(&log, "a", Duration::ZERO, false),
+				step(&log, "a", Duration::ZERO, false),
+				step(&log, "b", Duration::ZERO, false).depends_on("missing"),
+			])
+			.await,
+		);
+
+		assert!(log.borrow().is_empty());
+		assert_eq!(
+			errors(&err.failures),
+			[
+				("a", "duplicate step name".to_owned()),
+				("b", "depends on unknown step `missing`".to_owned()),
+			]
+		);
+	}
+}
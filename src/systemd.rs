@@ -0,0 +1,372 @@
+use std::{
+	io,
+	os::{
+		fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+		unix::net::UnixDatagram,
+	},
+	sync::atomic::{AtomicBool, Ordering},
+	time::Duration,
+};
+
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::listen::Listen;
+
+/// The first file descriptor systemd passes, after stdin, stdout and stderr.
+const LISTEN_FDS_START: RawFd = 3;
+
+static LISTENERS_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Sockets passed by systemd socket activation
+#[derive(Default)]
+pub(crate) struct Activated {
+	pub(crate) grpc: Option<Listen>,
+	pub(crate) http: Option<Listen>,
+}
+
+/// Take the listeners systemd passed via `LISTEN_FDS`
+///
+/// Sockets named `grpc` and `http` (`FileDescriptorName=`) are used for those servers; unnamed
+/// sockets are assigned in order, gRPC first. The sockets can only be taken once per process, and
+/// the `LISTEN_*` variables are removed so child processes do not inherit them.
+///
+/// # Errors
+///
+/// Returns an error if `LISTEN_FDS` is invalid, or if a passed descriptor is not a listening
+/// stream socket or cannot be registered with the runtime.
+pub(crate) fn listeners() -> io::Result<Activated> {
+	let fds = passed_fds(
+		std::env::var("LISTEN_PID").ok().as_deref(),
+		std::env::var("LISTEN_FDS").ok().as_deref(),
+	)?;
+	if fds == 0 || LISTENERS_TAKEN.swap(true, Ordering::SeqCst) {
+		return Ok(Activated::default());
+	}
+
+	let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+	for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+		// SAFETY: std serialises its own environment access, and runesys does not read these
+		// variables through libc.
+		unsafe { std::env::remove_var(var) };
+	}
+
+	let listeners = (LISTEN_FDS_START..LISTEN_FDS_START + fds)
+		.map(|fd| take_fd(fd).and_then(from_fd))
+		.collect::<io::Result<Vec<_>>>()?;
+	Ok(assign(listeners, &names))
+}
+
+/// The number of sockets passed to this process, or 0 if `LISTEN_PID` names another process
+fn passed_fds(pid: Option<&str>, fds: Option<&str>) -> io::Result<RawFd> {
+	let (Some(pid), Some(fds)) = (pid, fds) else {
+		return Ok(0);
+	};
+	if pid.parse() != Ok(std::process::id()) {
+		return Ok(0);
+	}
+	fds.parse()
+		.ok()
+		.filter(|fds| (0..=RawFd::MAX - LISTEN_FDS_START).contains(fds))
+		.ok_or_else(|| {
+			io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!("invalid LISTEN_FDS {fds:?}"),
+			)
+		})
+}
+
+/// Take ownership of a passed descriptor, and stop it leaking into child processes
+fn take_fd(fd: RawFd) -> io::Result<OwnedFd> {
+	// SAFETY: F_GETFD and F_SETFD only read and set the descriptor flags.
+	let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+	if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } == -1 {
+		return Err(io::Error::last_os_error());
+	}
+	// SAFETY: `fd` is open, LISTEN_PID matches this process so systemd passed it ownership of
+	// the descriptor, and the descriptors are only taken once.
+	Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Assign `listeners` to the servers by their `LISTEN_FDNAMES` names, unnamed ones in order
+fn assign(listeners: Vec<Listen>, names: &str) -> Activated {
+	let mut activated = Activated::default();
+	let mut names = names.split(':');
+	let mut unnamed = Vec::new();
+	for listener in listeners {
+		match names.next() {
+			Some("grpc") => activated.grpc = Some(listener),
+			Some("http") => activated.http = Some(listener),
+			_ => unnamed.push(listener),
+		}
+	}
+
+	let mut unnamed = unnamed.into_iter();
+	if activated.grpc.is_none() {
+		activated.grpc = unnamed.next();
+	}
+	if activated.http.is_none() {
+		activated.http = unnamed.next();
+	}
+	if unnamed.len() > 0 {
+		warn!("Ignoring {} unused socket(s) from systemd", unnamed.len());
+	}
+	activated
+}
+
+fn from_fd(fd: OwnedFd) -> io::Result<Listen> {
+	if sockopt(&fd, libc::SO_TYPE)? != libc::SOCK_STREAM || sockopt(&fd, libc::SO_ACCEPTCONN)? == 0
+	{
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!(
+				"descriptor {} from systemd is not a listening stream socket",
+				fd.as_raw_fd()
+			),
+		));
+	}
+
+	let tcp = std::net::TcpListener::from(fd);
+	if tcp.local_addr().is_ok() {
+		tcp.set_nonblocking(true)?;
+		return Ok(Listen::Listener(tokio::net::TcpListener::from_std(tcp)?));
+	}
+
+	let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+	unix.set_nonblocking(true)?;
+	Ok(Listen::UnixListener(tokio::net::UnixListener::from_std(
+		unix,
+	)?))
+}
+
+/// Read an integer `SOL_SOCKET` option.
+fn sockopt(fd: &OwnedFd, option: libc::c_int) -> io::Result<libc::c_int> {
+	let mut value: libc::c_int = 0;
+	let mut len =
+		libc::socklen_t::try_from(size_of::<libc::c_int>()).expect("c_int fits socklen_t");
+	// SAFETY: `value` and `len` describe a valid c_int buffer for the duration of the call.
+	let res = unsafe {
+		libc::getsockopt(
+			fd.as_raw_fd(),
+			libc::SOL_SOCKET,
+			option,
+			(&raw mut value).cast(),
+			&raw mut len,
+		)
+	};
+	if res == -1 {
+		return Err(io::Error::last_os_error());
+	}
+	Ok(value)
+}
+
+/// Send `state` to the service manager, if runesys runs under systemd with `Type=notify`.
+pub(crate) fn notify(state: &str) {
+	let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+		return;
+	};
+
+	let res = UnixDatagram::unbound().and_then(|socket| {
+		#[cfg(target_os = "linux")]
+		if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+			use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+			return SocketAddr::from_abstract_name(name)
+				.and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr));
+		}
+		socket.send_to(state.as_bytes(), &path)
+	});
+	if let Err(err) = res {
+		warn!("Failed to notify systemd of {state:?}: {err}");
+	}
+}
+
+/// Ping the systemd watchdog at half of `WATCHDOG_USEC` until `shutdown` is cancelled.
+pub(crate) fn watchdog(shutdown: &CancellationToken) {
+	let usec = match std::env::var("WATCHDOG_PID") {
+		Ok(pid) if pid.parse() != Ok(std::process::id()) => return,
+		_ => std::env::var("WATCHDOG_USEC")
+			.ok()
+			.and_then(|u| u.parse().ok()),
+	};
+	let Some(usec) = usec.filter(|&usec: &u64| usec > 0) else {
+		return;
+	};
+
+	let period = Duration::from_micros(usec) / 2;
+	info!("Pinging the systemd watchdog every {period:?}");
+	let shutdown = shutdown.clone();
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(period);
+		loop {
+			tokio::select! {
+				_ = interval.tick() => notify("WATCHDOG=1"),
+				() = shutdown.cancelled() => return,
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		net::{TcpListener, TcpStream, UdpSocket},
+		os::unix::net::UnixListener,
+		path::PathBuf,
+		sync::PoisonError,
+	};
+
+	use super::*;
+
+	/// A socket path in the temporary directory, unique to this process and test.
+	fn socket_path(name: &str) -> PathBuf {
+		let path = std::env::temp_dir().join(format!("runesys-{}-{name}.sock", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		path
+	}
+
+	fn recv(socket: &UnixDatagram) -> String {
+		let mut buf = [0; 64];
+		let len = socket.recv(&mut buf).expect("no notification received");
+		String::from_utf8_lossy(&buf[..len]).into_owned()
+	}
+
+	fn port(listener: Option<&Listen>) -> Option<u16> {
+		match listener {
+			Some(Listen::Listener(listener)) => listener.local_addr().ok().map(|addr| addr.port()),
+			_ => None,
+		}
+	}
+
+	fn tcp_listener() -> Listen {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		from_fd(OwnedFd::from(listener)).unwrap()
+	}
+
+	#[test]
+	fn notifies_the_service_manager() {
+		let _env = crate::ENV_LOCK
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		let path = socket_path("notify");
+		let socket = UnixDatagram::bind(&path).unwrap();
+		socket
+			.set_read_timeout(Some(Duration::from_secs(5)))
+			.unwrap();
+		// SAFETY: environment changes in tests are serialised by ENV_LOCK.
+		unsafe {
+			std::env::set_var("NOTIFY_SOCKET", &path);
+			std::env::set_var("WATCHDOG_USEC", "20000");
+			std::env::remove_var("WATCHDOG_PID");
+		}
+
+		notify("READY=1");
+		assert_eq!(recv(&socket), "READY=1");
+
+		let runtime = tokio::runtime::Builder::new_current_thread()
+			.enable_time()
+			.build()
+			.unwrap();
+		let shutdown = CancellationToken::new();
+		runtime.block_on(async {
+			watchdog(&shutdown);
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		});
+		shutdown.cancel();
+		assert_eq!(recv(&socket), "WATCHDOG=1");
+		assert_eq!(recv(&socket), "WATCHDOG=1");
+
+		notify("STOPPING=1");
+		while recv(&socket) == "WATCHDOG=1" {}
+
+		// SAFETY: environment changes in tests are serialised by ENV_LOCK.
+		unsafe {
+			std::env::remove_var("NOTIFY_SOCKET");
+			std::env::remove_var("WATCHDOG_USEC");
+		}
+		let _ = std::fs::remove_file(path);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[test]
+	fn notifies_an_abstract_socket() {
+		use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+		let _env = crate::ENV_LOCK
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		let name = format!("runesys-{}-notify", std::process::id());
+		let socket =
+			UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+		socket
+			.set_read_timeout(Some(Duration::from_secs(5)))
+			.unwrap();
+		// SAFETY: environment changes in tests are serialised by ENV_LOCK.
+		unsafe { std::env::set_var("NOTIFY_SOCKET", format!("@{name}")) };
+
+		notify("STOPPING=1");
+		assert_eq!(recv(&socket), "STOPPING=1");
+
+		// SAFETY: environment changes in tests are serialised by ENV_LOCK.
+		unsafe { std::env::remove_var("NOTIFY_SOCKET") };
+	}
+
+	#[test]
+	fn parses_listen_fds() {
+		let pid = std::process::id().to_string();
+		let other = (std::process::id() + 1).to_string();
+
+		assert_eq!(passed_fds(None, None).unwrap(), 0);
+		assert_eq!(passed_fds(Some(&pid), None).unwrap(), 0);
+		assert_eq!(passed_fds(Some(&other), Some("2")).unwrap(), 0);
+		assert_eq!(passed_fds(Some(&pid), Some("2")).unwrap(), 2);
+		for invalid in ["-1", "two", "2147483647"] {
+			assert!(passed_fds(Some(&pid), Some(invalid)).is_err(), "{invalid}");
+		}
+	}
+
+	#[tokio::test]
+	async fn assigns_named_sockets_then_unnamed_in_order() {
+		let listeners = vec![tcp_listener(), tcp_listener(), tcp_listener()];
+		let ports: Vec<_> = listeners.iter().map(|l| port(Some(l))).collect();
+
+		let activated = assign(listeners, "metrics:http:other");
+		assert_eq!(port(activated.http.as_ref()), ports[1]);
+		assert_eq!(port(activated.grpc.as_ref()), ports[0]);
+
+		let listeners = vec![tcp_listener(), tcp_listener()];
+		let ports: Vec<_> = listeners.iter().map(|l| port(Some(l))).collect();
+		let activated = assign(listeners, "");
+		assert_eq!(port(activated.grpc.as_ref()), ports[0]);
+		assert_eq!(port(activated.http.as_ref()), ports[1]);
+	}
+
+	#[tokio::test]
+	async fn accepts_only_listening_stream_sockets() {
+		assert!(matches!(tcp_listener(), Listen::Listener(_)));
+
+		let path = socket_path("listen");
+		let unix = UnixListener::bind(&path).unwrap();
+		assert!(matches!(
+			from_fd(OwnedFd::from(unix)),
+			Ok(Listen::UnixListener(_))
+		));
+		let _ = std::fs::remove_file(path);
+
+		let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+		assert!(from_fd(OwnedFd::from(udp)).is_err());
+
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		assert!(from_fd(OwnedFd::from(stream)).is_err());
+
+		let file = std::fs::File::open("/dev/null").unwrap();
+		assert!(from_fd(OwnedFd::from(file)).is_err());
+	}
+
+	#[test]
+	fn rejects_closed_descriptors() {
+		// Above any RLIMIT_NOFILE, so never open.
+		assert!(take_fd(RawFd::MAX - 1).is_err());
+	}
+}
@@ -0,0 +1,405 @@
+use std::{
+	borrow::Cow,
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tokio::{task::JoinSet, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::error::{Error, Result};
+
+type TaskFactory = Box<dyn FnMut(CancellationToken) -> BoxFuture<'static, Result<()>> + Send>;
+
+/// What the supervisor does when a background task returns an error or panics
+///
+/// A task that returns `Ok(())` is considered finished and is never restarted.
+#[derive(Debug, Clone, Copy)]
+pub enum TaskPolicy {
+	/// Restart the task after every failure.
+	Restart(Backoff),
+	/// Restart the task up to the given number of consecutive times, then fail the service.
+	///
+	/// A task that stays up for longer than the backoff's `max` before failing again starts
+	/// counting from zero.
+	RestartTimes(u32, Backoff),
+	/// Shut the whole service down.
+	FailService,
+	/// Log the failure and leave the task stopped.
+	Ignore,
+}
+
+/// Exponential backoff between restarts, doubling from `initial` up to `max`
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+	pub initial: Duration,
+	pub max: Duration,
+}
+
+impl Default for Backoff {
+	fn default() -> Self {
+		Self {
+			initial: Duration::from_secs(1),
+			max: Duration::from_mins(1),
+		}
+	}
+}
+
+impl Backoff {
+	/// Delay before the restart following `restarts` previous restarts
+	#[must_use]
+	pub fn delay(&self, restarts: u32) -> Duration {
+		self.initial
+			.checked_mul(1 << restarts.min(31))
+			.map_or(self.max, |delay| delay.min(self.max))
+	}
+}
+
+/// A named background task run under the service supervisor
+pub struct Task {
+	name: Cow<'static, str>,
+	policy: TaskPolicy,
+	shutdown_timeout: Option<Duration>,
+	factory: TaskFactory,
+}
+
+impl Task {
+	/// Create a task that is (re)started by calling `factory`
+	///
+	/// The factory is handed a [`CancellationToken`] that is cancelled when the service shuts down.
+	/// By default a failing task fails the service.
+	pub fn new<F, Fut>(name: impl Into<Cow<'static, str>>, mut factory: F) -> Self
+	where
+		F: FnMut(CancellationToken) -> Fut + Send + 'static,
+		Fut: Future<Output = Result<()>> + Send + 'static,
+	{
+		Self {
+			name: name.into(),
+			policy: TaskPolicy::FailService,
+			shutdown_timeout: None,
+			factory: Box::new(move |token| Box::pin(factory(token))),
+		}
+	}
+
+	/// Create a task from a future that can only be started once
+	pub(crate) fn once<F, Fut>(name: impl Into<Cow<'static, str>>, f: F) -> Self
+	where
+		F: FnOnce(CancellationToken) -> Fut + Send + 'static,
+		Fut: Future<Output = Result<()>> + Send + 'static,
+	{
+		let mut f = Some(f);
+		Self::new(name, move |token| {
+			let f = f.take().expect("one-shot task cannot be restarted");
+			f(token)
+		})
+	}
+
+	/// Set what happens when the task fails
+	#[must_use]
+	pub fn policy(mut self, policy: TaskPolicy) -> Self {
+		self.policy = policy;
+		self
+	}
+
+	/// How long the task may keep running after shutdown begins, before it is aborted
+	///
+	/// Defaults to [`Config::shutdown_timeout`](crate::config::Config::shutdown_timeout).
+	#[must_use]
+	pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+		self.shutdown_timeout = Some(timeout);
+		self
+	}
+
+	#[must_use]
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+}
+
+/// What a supervised task is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+	Running,
+	/// Waiting out the backoff before a restart.
+	Restarting,
+	/// Returned `Ok(())`.
+	Finished,
+	/// Failed and will not be restarted.
+	Failed,
+	/// Stopped by shutdown.
+	Stopped,
+}
+
+/// The state of a supervised task, as listed by the admin server
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+	pub name: Cow<'static, str>,
+	pub state: TaskState,
+	/// Consecutive restarts, reset once the task stays up for longer than its backoff's `max`.
+	pub restarts: u32,
+}
+
+/// The state of every supervised task, shared between the supervisors and the admin server.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TaskStatuses(Arc<Mutex<Vec<TaskStatus>>>);
+
+impl TaskStatuses {
+	fn register(&self, name: Cow<'static, str>) -> usize {
+		let mut statuses = self.0.lock().expect("task status lock poisoned");
+		statuses.push(TaskStatus {
+			name,
+			state: TaskState::Running,
+			restarts: 0,
+		});
+		statuses.len() - 1
+	}
+
+	fn set(&self, index: usize, state: TaskState, restarts: u32) {
+		let mut statuses = self.0.lock().expect("task status lock poisoned");
+		statuses[index].state = state;
+		statuses[index].restarts = restarts;
+	}
+
+	pub(crate) fn snapshot(&self) -> Vec<TaskStatus> {
+		self.0.lock().expect("task status lock poisoned").clone()
+	}
+}
+
+/// Spawn a supervisor for every task, reporting their state to `statuses`
+///
+/// Each supervisor resolves to `Ok(())` once its task has stopped for good, or to an error if the
+/// task's policy says the service should fail.
+pub(crate) fn supervise(
+	tasks: Vec<Task>,
+	shutdown: &CancellationToken,
+	statuses: &TaskStatuses,
+) -> JoinSet<Result<()>> {
+	let default_timeout = Duration::from_secs(crate::config::config().shutdown_timeout);
+
+	let mut set = JoinSet::new();
+	for task in tasks {
+		let index = statuses.register(task.name.clone());
+		set.spawn(supervise_task(
+			task,
+			shutdown.clone(),
+			default_timeout,
+			statuses.clone(),
+			index,
+		));
+	}
+	set
+}
+
+async fn supervise_task(
+	mut task: Task,
+	shutdown: CancellationToken,
+	default_timeout: Duration,
+	statuses: TaskStatuses,
+	index: usize,
+) -> Result<()> {
+	let name = task.name.as_ref();
+	let shutdown_timeout = task.shutdown_timeout.unwrap_or(default_timeout);
+	let mut restarts = 0;
+
+	loop {
+		statuses.set(index, TaskState::Running, restarts);
+		let started = Instant::now();
+		let mut handle = tokio::spawn((task.factory)(shutdown.child_token()));
+
+		let res = tokio::select! {
+			res = &mut handle => res.map_err(Error::from).and_then(|r| r),
+			() = async {
+				shutdown.cancelled().await;
+				tokio::time::sleep(shutdown_timeout).await;
+			} => {
+				handle.abort();
+				if !shutdown_timeout.is_zero() {
+					warn!(task = name, "Background task did not stop within {shutdown_timeout:?}, aborting");
+				}
+				statuses.set(index, TaskState::Stopped, restarts);
+				return Ok(());
+			}
+		};
+
+		let err = match res {
+			Ok(()) => {
+				info!(task = name, "Background task finished");
+				statuses.set(index, TaskState::Finished, restarts);
+				return Ok(());
+			}
+			Err(err) if shutdown.is_cancelled() => {
+				error!(task = name, "Background task failed during shutdown: {err}");
+				statuses.set(index, TaskState::Failed, restarts);
+				return Ok(());
+			}
+			Err(err) => err,
+		};
+
+		error!(
+			monotonic_counter.runesys.task.failures = 1_u64,
+			task = name,
+			"Background task failed: {err}"
+		);
+
+		if let TaskPolicy::Restart(backoff) | TaskPolicy::RestartTimes(_, backoff) = task.policy
+			&& started.elapsed() > backoff.max
+		{
+			restarts = 0;
+		}
+
+		let backoff = match task.policy {
+			TaskPolicy::Restart(backoff) => backoff,
+			TaskPolicy::RestartTimes(max, backoff) if restarts < max => backoff,
+			TaskPolicy::Ignore => {
+				statuses.set(index, TaskState::Failed, restarts);
+				return Ok(());
+			}
+			TaskPolicy::RestartTimes(..) | TaskPolicy::FailService => {
+				statuses.set(index, TaskState::Failed, restarts);
+				return Err(Error::Task {
+					name: task.name.clone(),
+					source: Box::new(err),
+				});
+			}
+		};
+
+		let delay = backoff.delay(restarts);
+		restarts += 1;
+		statuses.set(index, TaskState::Restarting, restarts);
+		warn!(
+			monotonic_counter.runesys.task.restarts = 1_u64,
+			task = name,
+			"Restarting background task in {delay:?}"
+		);
+
+		tokio::select! {
+			() = tokio::time::sleep(delay) => {}
+			() = shutdown.cancelled() => {
+				statuses.set(index, TaskState::Stopped, restarts);
+				return Ok(());
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicU32, Ordering};
+
+	use super::*;
+
+	const BACKOFF: Backoff = Backoff {
+		initial: Duration::from_secs(1),
+		max: Duration::from_secs(4),
+	};
+
+	/// A task that counts its runs, and fails after `up` on every run before `finish_after`.
+	fn counted(up: Duration, finish_after: u32) -> (Task, Arc<AtomicU32>) {
+		let runs = Arc::new(AtomicU32::new(0));
+		let counter = runs.clone();
+		let task = Task::new("counted", move |_| {
+			let run = counter.fetch_add(1, Ordering::SeqCst) + 1;
+			async move {
+				tokio::time::sleep(up).await;
+				if run >= finish_after {
+					Ok(())
+				} else {
+					Err(Error::Config(format!("run {run} failed")))
+				}
+			}
+		});
+		(task, runs)
+	}
+
+	async fn run(task: Task, shutdown: CancellationToken) -> (Result<()>, TaskStatus) {
+		let statuses = TaskStatuses::default();
+		let index = statuses.register(task.name.clone());
+		let res = supervise_task(task, shutdown, Duration::ZERO, statuses.clone(), index).await;
+		(res, statuses.snapshot().remove(index))
+	}
+
+	#[test]
+	fn backoff_doubles_up_to_max() {
+		let delays: Vec<_> = (0..5).map(|restarts| BACKOFF.delay(restarts)).collect();
+		assert_eq!(delays, [1, 2, 4, 4, 4].map(Duration::from_secs));
+		assert_eq!(BACKOFF.delay(u32::MAX), BACKOFF.max);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn restart_waits_out_the_backoff() {
+		let (task, runs) = counted(Duration::ZERO, 4);
+		let started = Instant::now();
+		let (res, status) = run(
+			task.policy(TaskPolicy::Restart(BACKOFF)),
+			CancellationToken::new(),
+		)
+		.await;
+
+		assert!(res.is_ok());
+		assert_eq!(runs.load(Ordering::SeqCst), 4);
+		assert_eq!(started.elapsed(), Duration::from_secs(1 + 2 + 4));
+		assert_eq!((status.state, status.restarts), (TaskState::Finished, 3));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn restart_times_fails_the_service_after_the_limit() {
+		let (task, runs) = counted(Duration::ZERO, u32::MAX);
+		let (res, status) = run(
+			task.policy(TaskPolicy::RestartTimes(2, BACKOFF)),
+			CancellationToken::new(),
+		)
+		.await;
+
+		assert!(matches!(res, Err(Error::Task { .. })));
+		assert_eq!(runs.load(Ordering::SeqCst), 3);
+		assert_eq!((status.state, status.restarts), (TaskState::Failed, 2));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn restart_times_counts_consecutive_failures() {
+		let (task, runs) = counted(BACKOFF.max * 2, 5);
+		let (res, status) = run(
+			task.policy(TaskPolicy::RestartTimes(1, BACKOFF)),
+			CancellationToken::new(),
+		)
+		.await;
+
+		assert!(res.is_ok());
+		assert_eq!(runs.load(Ordering::SeqCst), 5);
+		assert_eq!((status.state, status.restarts), (TaskState::Finished, 1));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn fail_service_and_ignore_do_not_restart() {
+		let (task, runs) = counted(Duration::ZERO, u32::MAX);
+		let (res, _) = run(task, CancellationToken::new()).await;
+		assert!(matches!(res, Err(Error::Task { .. })));
+		assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+		let (task, runs) = counted(Duration::ZERO, u32::MAX);
+		let (res, status) = run(task.policy(TaskPolicy::Ignore), CancellationToken::new()).await;
+		assert!(res.is_ok());
+		assert_eq!(runs.load(Ordering::SeqCst), 1);
+		assert_eq!(status.state, TaskState::Failed);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn shutdown_interrupts_the_backoff() {
+		let (task, runs) = counted(Duration::ZERO, u32::MAX);
+		let shutdown = CancellationToken::new();
+		let cancel = shutdown.clone();
+		tokio::spawn(async move {
+			tokio::time::sleep(Duration::from_millis(1500)).await;
+			cancel.cancel();
+		});
+		let (res, status) = run(task.policy(TaskPolicy::Restart(BACKOFF)), shutdown).await;
+
+		assert!(res.is_ok());
+		assert_eq!(runs.load(Ordering::SeqCst), 2);
+		assert_eq!(status.state, TaskState::Stopped);
+	}
+}
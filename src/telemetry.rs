@@ -13,6 +13,9 @@ use opentelemetry_semantic_conventions::{
 
 use crate::ServiceInfo;
 
+/// # Panics
+///
+/// Panics if the OTLP metric exporter cannot be created.
 #[must_use]
 pub fn init_meter_provider(info: &ServiceInfo) -> SdkMeterProvider {
 	let exporter = MetricExporter::builder()
@@ -34,6 +37,9 @@ pub fn init_meter_provider(info: &ServiceInfo) -> SdkMeterProvider {
 	meter_provider
 }
 
+/// # Panics
+///
+/// Panics if the OTLP span exporter cannot be created.
 #[must_use]
 pub fn init_tracer_provider(info: &ServiceInfo) -> SdkTracerProvider {
 	let exporter = SpanExporter::builder()
@@ -60,16 +66,7 @@ impl From<&ServiceInfo> for Resource {
 				[
 					KeyValue::new(SERVICE_NAME, value.pkg),
 					KeyValue::new(SERVICE_VERSION, value.version),
-					KeyValue::new(
-						DEPLOYMENT_ENVIRONMENT_NAME,
-						if let Ok(env) = std::env::var("ENVIRONMENT") {
-							env
-						} else if cfg!(debug_assertions) {
-							"development".to_string()
-						} else {
-							"production".to_string()
-						},
-					),
+					KeyValue::new(DEPLOYMENT_ENVIRONMENT_NAME, crate::config::environment()),
 				],
 				SCHEMA_URL,
 			)
@@ -107,7 +104,7 @@ pub mod propagation {
 
 	struct MetadataExtractor<'a>(&'a MetadataMap);
 
-	impl<'a> Extractor for MetadataExtractor<'a> {
+	impl Extractor for MetadataExtractor<'_> {
 		fn get(&self, key: &str) -> Option<&str> {
 			self.0.get(key).and_then(|metadata| metadata.to_str().ok())
 		}
@@ -125,10 +122,10 @@ pub mod propagation {
 
 	impl Injector for MetadataInjector<'_> {
 		fn set(&mut self, key: &str, value: String) {
-			if let Ok(key) = MetadataKey::from_str(key) {
-				if let Ok(val) = value.parse() {
-					self.0.insert(key, val);
-				}
+			if let Ok(key) = MetadataKey::from_str(key)
+				&& let Ok(val) = value.parse()
+			{
+				self.0.insert(key, val);
 			}
 		}
 	}
@@ -0,0 +1,664 @@
+use std::{
+	io,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	sync::{Arc, RwLock},
+	task::{Context, Poll},
+	time::Duration,
+};
+
+use futures::Stream;
+use thiserror::Error;
+use tokio::{
+	net::{TcpListener, TcpStream},
+	sync::mpsc,
+};
+use tokio_rustls::{
+	rustls::{
+		RootCertStore, ServerConfig,
+		pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+		server::{WebPkiClientVerifier, danger::ClientCertVerifier},
+	},
+	server::TlsStream,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::config::Config;
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The verified certificate chain a client presented over mutual TLS
+///
+/// Inserted as a request extension on both the gRPC and HTTP listeners when the client
+/// authenticated with a certificate.
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+	chain: Arc<Vec<CertificateDer<'static>>>,
+}
+
+impl PeerIdentity {
+	fn new(chain: Arc<Vec<CertificateDer<'static>>>) -> Option<Self> {
+		(!chain.is_empty()).then_some(Self { chain })
+	}
+
+	/// The client's end-entity certificate
+	#[must_use]
+	pub fn certificate(&self) -> &CertificateDer<'static> {
+		&self.chain[0]
+	}
+
+	/// The full chain the client presented, starting with its end-entity certificate
+	#[must_use]
+	pub fn chain(&self) -> &[CertificateDer<'static>] {
+		&self.chain
+	}
+}
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+	#[error("tls_cert and tls_key must be set together")]
+	Incomplete,
+	#[error("failed to read {}", path.display())]
+	Read {
+		path: PathBuf,
+		#[source]
+		source: io::Error,
+	},
+	#[error("failed to parse {}", path.display())]
+	Pem {
+		path: PathBuf,
+		#[source]
+		source: tokio_rustls::rustls::pki_types::pem::Error,
+	},
+	#[error("no certificates in {}", .0.display())]
+	NoCertificates(PathBuf),
+	#[error("invalid client CA")]
+	ClientCa(#[from] tokio_rustls::rustls::server::VerifierBuilderError),
+	#[error("invalid certificate or key")]
+	Rustls(#[from] tokio_rustls::rustls::Error),
+}
+
+/// Paths of the PEM files TLS is configured from.
+#[derive(Debug, Clone)]
+struct TlsFiles {
+	cert: PathBuf,
+	key: PathBuf,
+	client_ca: Option<PathBuf>,
+}
+
+impl TlsFiles {
+	fn from_config(config: &Config) -> Result<Option<Self>, TlsError> {
+		match (&config.tls_cert, &config.tls_key) {
+			(Some(cert), Some(key)) => Ok(Some(Self {
+				cert: cert.clone(),
+				key: key.clone(),
+				client_ca: config.tls_client_ca.clone(),
+			})),
+			(None, None) => Ok(None),
+			_ => Err(TlsError::Incomplete),
+		}
+	}
+
+	fn read(&self) -> Result<Vec<Vec<u8>>, TlsError> {
+		[Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+			.into_iter()
+			.flatten()
+			.map(|path| read(path))
+			.collect()
+	}
+
+	fn server_config(&self, alpn: &[&[u8]]) -> Result<ServerConfig, TlsError> {
+		let cert_pem = read(&self.cert)?;
+		let certs = CertificateDer::pem_slice_iter(&cert_pem)
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|source| pem_error(&self.cert, source))?;
+		if certs.is_empty() {
+			return Err(TlsError::NoCertificates(self.cert.clone()));
+		}
+		let key = PrivateKeyDer::from_pem_slice(&read(&self.key)?)
+			.map_err(|source| pem_error(&self.key, source))?;
+
+		let builder = ServerConfig::builder().with_client_cert_verifier(self.client_verifier()?);
+		let mut config = builder.with_single_cert(certs, key)?;
+		config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+		Ok(config)
+	}
+
+	fn client_verifier(&self) -> Result<Arc<dyn ClientCertVerifier>, TlsError> {
+		let Some(path) = &self.client_ca else {
+			return Ok(WebPkiClientVerifier::no_client_auth());
+		};
+
+		let mut roots = RootCertStore::empty();
+		for cert in CertificateDer::pem_slice_iter(&read(path)?) {
+			roots.add(cert.map_err(|source| pem_error(path, source))?)?;
+		}
+		if roots.is_empty() {
+			return Err(TlsError::NoCertificates(path.clone()));
+		}
+		Ok(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+	}
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+	std::fs::read(path).map_err(|source| TlsError::Read {
+		path: path.to_path_buf(),
+		source,
+	})
+}
+
+fn pem_error(path: &Path, source: tokio_rustls::rustls::pki_types::pem::Error) -> TlsError {
+	TlsError::Pem {
+		path: path.to_path_buf(),
+		source,
+	}
+}
+
+/// A TLS acceptor whose certificates are reloaded when the files on disk change
+#[derive(Clone)]
+pub(crate) struct TlsAcceptor {
+	config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsAcceptor {
+	/// Build an acceptor from [`Config`], or `None` if TLS is not configured
+	///
+	/// The files are polled every [`tls_reload_interval`] seconds until `shutdown` is cancelled.
+	/// A rotation that fails to load is logged and the previous certificates stay in use.
+	///
+	/// [`tls_reload_interval`]: Config::tls_reload_interval
+	pub(crate) fn from_config(
+		config: &Config,
+		alpn: &'static [&'static [u8]],
+		shutdown: &CancellationToken,
+	) -> Result<Option<Self>, TlsError> {
+		let Some(files) = TlsFiles::from_config(config)? else {
+			return Ok(None);
+		};
+
+		let contents = files.read()?;
+		let acceptor = Self {
+			config: Arc::new(RwLock::new(Arc::new(files.server_config(alpn)?))),
+		};
+
+		let interval = Duration::from_secs(config.tls_reload_interval);
+		if !interval.is_zero() {
+			tokio::spawn(
+				acceptor
+					.clone()
+					.watch(files, contents, alpn, interval, shutdown.clone()),
+			);
+		}
+		Ok(Some(acceptor))
+	}
+
+	async fn watch(
+		self,
+		files: TlsFiles,
+		mut contents: Vec<Vec<u8>>,
+		alpn: &'static [&'static [u8]],
+		interval: Duration,
+		shutdown: CancellationToken,
+	) {
+		let mut interval = tokio::time::interval(interval);
+		interval.tick().await;
+
+		loop {
+			tokio::select! {
+				_ = interval.tick() => {}
+				() = shutdown.cancelled() => return,
+			}
+
+			let current = match files.read() {
+				Ok(current) if current == contents => continue,
+				Ok(current) => current,
+				Err(err) => {
+					error!("Failed to read TLS files: {err}");
+					continue;
+				}
+			};
+
+			match files.server_config(alpn) {
+				Ok(config) => {
+					*self.config.write().expect("TLS config lock poisoned") = Arc::new(config);
+					contents = current;
+					info!("Reloaded TLS certificates from {}", files.cert.display());
+				}
+				Err(err) => error!("Failed to reload TLS certificates: {err}"),
+			}
+		}
+	}
+
+	fn current(&self) -> tokio_rustls::TlsAcceptor {
+		tokio_rustls::TlsAcceptor::from(Arc::clone(
+			&self.config.read().expect("TLS config lock poisoned"),
+		))
+	}
+}
+
+/// A TCP listener that completes TLS handshakes in the background
+///
+/// A slow handshake does not hold up other connections; connections that fail the handshake
+/// are logged and dropped.
+pub(crate) struct TlsListener {
+	rx: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+	#[cfg_attr(not(feature = "http"), allow(dead_code))]
+	local_addr: SocketAddr,
+}
+
+impl TlsListener {
+	pub(crate) fn new(listener: TcpListener, acceptor: TlsAcceptor) -> io::Result<Self> {
+		let local_addr = listener.local_addr()?;
+		let (tx, rx) = mpsc::channel(64);
+		tokio::spawn(accept_loop(listener, acceptor, tx));
+		Ok(Self { rx, local_addr })
+	}
+
+	/// The accepted connections as a stream, for tonic's `serve_with_incoming`
+	pub(crate) fn into_stream(mut self) -> impl Stream<Item = io::Result<TlsStream<TcpStream>>> {
+		futures::stream::poll_fn(move |cx: &mut Context<'_>| match self.rx.poll_recv(cx) {
+			Poll::Ready(conn) => Poll::Ready(conn.map(|(io, _)| Ok(io))),
+			Poll::Pending => Poll::Pending,
+		})
+	}
+}
+
+#[cfg(feature = "http")]
+impl axum::serve::Listener for TlsListener {
+	type Io = TlsStream<TcpStream>;
+	type Addr = SocketAddr;
+
+	async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+		match self.rx.recv().await {
+			Some(conn) => conn,
+			// The accept loop only stops once the listener is dropped.
+			None => std::future::pending().await,
+		}
+	}
+
+	fn local_addr(&self) -> io::Result<Self::Addr> {
+		Ok(self.local_addr)
+	}
+}
+
+async fn accept_loop(
+	listener: TcpListener,
+	acceptor: TlsAcceptor,
+	tx: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+	loop {
+		let (stream, addr) = tokio::select! {
+			res = listener.accept() => match res {
+				Ok(conn) => conn,
+				Err(err) => {
+					warn!("Failed to accept connection: {err}");
+					if !is_connection_error(&err) {
+						tokio::time::sleep(Duration::from_secs(1)).await;
+					}
+					continue;
+				}
+			},
+			() = tx.closed() => return,
+		};
+
+		let acceptor = acceptor.current();
+		let tx = tx.clone();
+		tokio::spawn(async move {
+			match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+				Ok(Ok(stream)) => {
+					let _ = tx.send((stream, addr)).await;
+				}
+				Ok(Err(err)) => debug!("TLS handshake with {addr} failed: {err}"),
+				Err(_) => debug!("TLS handshake with {addr} timed out"),
+			}
+		});
+	}
+}
+
+fn is_connection_error(err: &io::Error) -> bool {
+	matches!(
+		err.kind(),
+		io::ErrorKind::ConnectionRefused
+			| io::ErrorKind::ConnectionAborted
+			| io::ErrorKind::ConnectionReset
+	)
+}
+
+/// Copy the client certificate tonic recorded for the connection into a [`PeerIdentity`].
+pub(crate) fn grpc_peer_identity<B>(mut req: axum::http::Request<B>) -> axum::http::Request<B> {
+	use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+
+	let peer = req
+		.extensions()
+		.get::<TlsConnectInfo<TcpConnectInfo>>()
+		.and_then(TlsConnectInfo::peer_certs)
+		.and_then(PeerIdentity::new);
+	if let Some(peer) = peer {
+		req.extensions_mut().insert(peer);
+	}
+	req
+}
+
+/// Make-service for `axum::serve` that hands each connection's [`PeerIdentity`] to the router.
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub(crate) struct HttpPeerIdentity(pub(crate) axum::Router);
+
+#[cfg(feature = "http")]
+impl tower::Service<axum::serve::IncomingStream<'_, TlsListener>> for HttpPeerIdentity {
+	type Response = WithPeerIdentity<axum::Router>;
+	type Error = std::convert::Infallible;
+	type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+	fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, stream: axum::serve::IncomingStream<'_, TlsListener>) -> Self::Future {
+		let peer = stream
+			.io()
+			.get_ref()
+			.1
+			.peer_certificates()
+			.and_then(|certs| PeerIdentity::new(Arc::new(certs.to_vec())));
+
+		std::future::ready(Ok(WithPeerIdentity {
+			inner: self.0.clone(),
+			peer,
+		}))
+	}
+}
+
+/// Inserts the connection's [`PeerIdentity`], if any, into every request.
+#[cfg(feature = "http")]
+#[derive(Clone)]
+pub(crate) struct WithPeerIdentity<S> {
+	inner: S,
+	peer: Option<PeerIdentity>,
+}
+
+#[cfg(feature = "http")]
+impl<S, B> tower::Service<axum::http::Request<B>> for WithPeerIdentity<S>
+where
+	S: tower::Service<axum::http::Request<B>>,
+{
+	type Response = S::Response;
+	type Error = S::Error;
+	type Future = S::Future;
+
+	fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+		self.inner.poll_ready(cx)
+	}
+
+	fn call(&mut self, mut req: axum::http::Request<B>) -> Self::Future {
+		if let Some(peer) = &self.peer {
+			req.extensions_mut().insert(peer.clone());
+		}
+		self.inner.call(req)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rcgen::{
+		BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair, KeyUsagePurpose,
+	};
+	use tokio_rustls::{
+		TlsConnector,
+		rustls::{ClientConfig, pki_types::ServerName},
+	};
+
+	use super::*;
+
+	const ALPN: &[&[u8]] = &[b"h2"];
+
+	/// A certificate authority generated for one test.
+	fn ca() -> CertifiedIssuer<'static, KeyPair> {
+		let mut params = CertificateParams::new(Vec::new()).unwrap();
+		params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+		params.key_usages = vec![KeyUsagePurpose::KeyCertSign];
+		CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+	}
+
+	/// A certificate for `localhost` signed by `ca`, and its key, as PEM.
+	fn leaf(ca: &CertifiedIssuer<'static, KeyPair>) -> (String, String) {
+		let key = KeyPair::generate().unwrap();
+		let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+		let cert = params.signed_by(&key, ca).unwrap();
+		(cert.pem(), key.serialize_pem())
+	}
+
+	/// A directory for the PEM files of one test, unique to this process.
+	fn dir(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("runesys-{}-tls-{name}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	/// A config serving the certificate and key written to `dir`.
+	fn config(dir: &Path, (cert, key): &(String, String)) -> Config {
+		std::fs::write(dir.join("cert.pem"), cert).unwrap();
+		std::fs::write(dir.join("key.pem"), key).unwrap();
+		Config {
+			tls_cert: Some(dir.join("cert.pem")),
+			tls_key: Some(dir.join("key.pem")),
+			tls_reload_interval: 1,
+			..Config::default()
+		}
+	}
+
+	fn files(config: &Config) -> TlsFiles {
+		TlsFiles::from_config(config).unwrap().unwrap()
+	}
+
+	/// A client trusting `ca`, presenting `identity` if set.
+	fn client(
+		ca: &CertifiedIssuer<'static, KeyPair>,
+		identity: Option<&(String, String)>,
+	) -> ClientConfig {
+		let mut roots = RootCertStore::empty();
+		roots.add(ca.der().clone()).unwrap();
+		let builder = ClientConfig::builder().with_root_certificates(roots);
+		match identity {
+			Some((cert, key)) => builder
+				.with_client_auth_cert(
+					vec![CertificateDer::from_pem_slice(cert.as_bytes()).unwrap()],
+					PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+				)
+				.unwrap(),
+			None => builder.with_no_client_auth(),
+		}
+	}
+
+	/// Complete a handshake between `client` and `acceptor`, returning the certificate the server
+	/// presented and the client's identity as the server saw it.
+	async fn handshake(
+		acceptor: &TlsAcceptor,
+		client: ClientConfig,
+	) -> io::Result<(CertificateDer<'static>, Option<PeerIdentity>)> {
+		let (client_io, server_io) = tokio::io::duplex(16 * 1024);
+		let connector = TlsConnector::from(Arc::new(client));
+		let server_name = ServerName::try_from("localhost").unwrap();
+		let (client, server) = tokio::join!(
+			connector.connect(server_name, client_io),
+			acceptor.current().accept(server_io),
+		);
+		let (client, server) = (client?, server?);
+
+		let presented = client.get_ref().1.peer_certificates().unwrap()[0].clone();
+		let peer = server
+			.get_ref()
+			.1
+			.peer_certificates()
+			.and_then(|certs| PeerIdentity::new(Arc::new(certs.to_vec())));
+		Ok((presented, peer))
+	}
+
+	fn der(pem: &str) -> CertificateDer<'static> {
+		CertificateDer::from_pem_slice(pem.as_bytes()).unwrap()
+	}
+
+	#[test]
+	fn requires_cert_and_key_together() {
+		assert!(TlsFiles::from_config(&Config::default()).unwrap().is_none());
+
+		let only_cert = Config {
+			tls_cert: Some("cert.pem".into()),
+			..Config::default()
+		};
+		assert!(matches!(
+			TlsFiles::from_config(&only_cert),
+			Err(TlsError::Incomplete)
+		));
+
+		let only_key = Config {
+			tls_key: Some("key.pem".into()),
+			tls_client_ca: Some("ca.pem".into()),
+			..Config::default()
+		};
+		assert!(matches!(
+			TlsFiles::from_config(&only_key),
+			Err(TlsError::Incomplete)
+		));
+	}
+
+	#[test]
+	fn builds_a_server_config_from_pem_files() {
+		let dir = dir("server-config");
+		let ca = ca();
+		let config = config(&dir, &leaf(&ca));
+
+		let server = files(&config).server_config(ALPN).unwrap();
+		assert_eq!(server.alpn_protocols, [b"h2".to_vec()]);
+
+		std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+		let mtls = Config {
+			tls_client_ca: Some(dir.join("ca.pem")),
+			..config
+		};
+		files(&mtls).server_config(ALPN).unwrap();
+	}
+
+	#[test]
+	fn reports_unusable_pem_files() {
+		let dir = dir("unusable");
+		let (cert, key) = leaf(&ca());
+		let config = config(&dir, &(cert.clone(), key.clone()));
+		let files = files(&config);
+
+		std::fs::write(&files.cert, &key).unwrap();
+		assert!(matches!(
+			files.server_config(ALPN),
+			Err(TlsError::NoCertificates(path)) if path == files.cert
+		));
+
+		std::fs::write(&files.cert, &cert).unwrap();
+		std::fs::write(&files.key, &cert).unwrap();
+		assert!(matches!(
+			files.server_config(ALPN),
+			Err(TlsError::Pem { path, .. }) if path == files.key
+		));
+
+		std::fs::remove_file(&files.key).unwrap();
+		assert!(matches!(
+			files.server_config(ALPN),
+			Err(TlsError::Read { path, .. }) if path == files.key
+		));
+
+		std::fs::write(&files.key, &key).unwrap();
+		std::fs::write(dir.join("ca.pem"), "").unwrap();
+		let files = TlsFiles {
+			client_ca: Some(dir.join("ca.pem")),
+			..files
+		};
+		assert!(matches!(
+			files.server_config(ALPN),
+			Err(TlsError::NoCertificates(path)) if path == dir.join("ca.pem")
+		));
+	}
+
+	#[tokio::test]
+	async fn verifies_client_certificates_against_the_client_ca() {
+		let dir = dir("mtls");
+		let ca = ca();
+		std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+		let config = Config {
+			tls_client_ca: Some(dir.join("ca.pem")),
+			tls_reload_interval: 0,
+			..config(&dir, &leaf(&ca))
+		};
+		let shutdown = CancellationToken::new();
+		let acceptor = TlsAcceptor::from_config(&config, ALPN, &shutdown)
+			.unwrap()
+			.unwrap();
+
+		let identity = leaf(&ca);
+		let (_, peer) = handshake(&acceptor, client(&ca, Some(&identity)))
+			.await
+			.unwrap();
+		let peer = peer.unwrap();
+		assert_eq!(peer.certificate(), &der(&identity.0));
+		assert_eq!(peer.chain().len(), 1);
+
+		assert!(handshake(&acceptor, client(&ca, None)).await.is_err());
+		let stranger = leaf(&self::ca());
+		assert!(
+			handshake(&acceptor, client(&ca, Some(&stranger)))
+				.await
+				.is_err()
+		);
+	}
+
+	#[tokio::test]
+	async fn does_not_ask_for_client_certificates_without_a_client_ca() {
+		let dir = dir("no-mtls");
+		let ca = ca();
+		let config = Config {
+			tls_reload_interval: 0,
+			..config(&dir, &leaf(&ca))
+		};
+		let acceptor = TlsAcceptor::from_config(&config, ALPN, &CancellationToken::new())
+			.unwrap()
+			.unwrap();
+
+		let (_, peer) = handshake(&acceptor, client(&ca, Some(&leaf(&ca))))
+			.await
+			.unwrap();
+		assert!(peer.is_none());
+		assert!(PeerIdentity::new(Arc::new(Vec::new())).is_none());
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn rotates_certificates_and_keeps_the_old_ones_when_broken() {
+		let dir = dir("rotation");
+		let ca = ca();
+		let first = leaf(&ca);
+		let config = config(&dir, &first);
+		let shutdown = CancellationToken::new();
+		let acceptor = TlsAcceptor::from_config(&config, ALPN, &shutdown)
+			.unwrap()
+			.unwrap();
+		let (presented, _) = handshake(&acceptor, client(&ca, None)).await.unwrap();
+		assert_eq!(presented, der(&first.0));
+
+		let second = leaf(&ca);
+		std::fs::write(dir.join("cert.pem"), &second.0).unwrap();
+		tokio::time::sleep(Duration::from_millis(1500)).await;
+		let (presented, _) = handshake(&acceptor, client(&ca, None)).await.unwrap();
+		assert_eq!(presented, der(&first.0), "cert and key no longer match");
+
+		std::fs::write(dir.join("key.pem"), &second.1).unwrap();
+		tokio::time::sleep(Duration::from_secs(1)).await;
+		let (presented, _) = handshake(&acceptor, client(&ca, None)).await.unwrap();
+		assert_eq!(presented, der(&second.0));
+
+		std::fs::write(dir.join("cert.pem"), "not a certificate").unwrap();
+		tokio::time::sleep(Duration::from_secs(1)).await;
+		let (presented, _) = handshake(&acceptor, client(&ca, None)).await.unwrap();
+		assert_eq!(presented, der(&second.0));
+
+		shutdown.cancel();
+	}
+}
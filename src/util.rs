@@ -10,6 +10,10 @@ use tracing::error;
 /// - `delay`: how long to wait between retries.
 ///
 /// Returns `Ok(T)` on the first successful attempt, or the last `Err(E)` if all retries fail.
+///
+/// # Errors
+///
+/// Returns the last error produced by `operation` once all retries are exhausted.
 pub async fn retry_async<Op, Fut, T, E>(
 	mut operation: Op,
 	mut retries: usize,
@@ -33,6 +37,12 @@ where
 	}
 }
 
+/// Decode a `prost_types::Any` into `T`.
+///
+/// # Errors
+///
+/// Returns `Status::invalid_argument` if the payload is not of type `T`.
+#[allow(clippy::result_large_err)]
 pub fn try_from_any<'a, T: TryFrom<&'a prost_types::Any> + prost::Name>(
 	any: &'a prost_types::Any,
 ) -> Result<T, tonic::Status> {
@@ -44,6 +54,8 @@ pub fn try_from_any<'a, T: TryFrom<&'a prost_types::Any> + prost::Name>(
 	})
 }
 
+/// Build a tonic interceptor that mutates every request with `mutator`.
+#[allow(clippy::result_large_err)]
 pub fn interceptor<T>(mutator: impl Fn(&mut T)) -> impl FnMut(T) -> Result<T, tonic::Status> {
 	move |mut value: T| {
 		mutator(&mut value);